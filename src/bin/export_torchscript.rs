@@ -0,0 +1,16 @@
+use cerke_dqn::learn::cerke::brain::{Brain, QNet};
+
+// export_torchscript <checkpoint directory> <output .pt>
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (checkpoint, output) = match (args.next(), args.next()) {
+        (Some(checkpoint), Some(output)) => (checkpoint, output),
+        _ => anyhow::bail!("usage: export_torchscript <checkpoint directory> <output .pt>"),
+    };
+
+    let mut qnet = QNet::new();
+    let iteration = qnet.load(&checkpoint)?;
+    qnet.export_torchscript(&output, iteration)?;
+    println!("{} (iteration {}) -> {}", checkpoint, iteration, output);
+    Ok(())
+}
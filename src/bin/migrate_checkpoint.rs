@@ -0,0 +1,18 @@
+use cerke_dqn::learn::cerke::brain::{Brain, QNet};
+
+// migrate_checkpoint <old checkpoint> <output directory>
+// rewrites a checkpoint, such as the `_learn.vs`/`_target.vs` pair the first versions saved,
+// as a checkpoint directory of this build
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (input, output) = match (args.next(), args.next()) {
+        (Some(input), Some(output)) => (input, output),
+        _ => anyhow::bail!("usage: migrate_checkpoint <old checkpoint> <output directory>"),
+    };
+
+    let mut qnet = QNet::new();
+    let iteration = qnet.load(&input)?;
+    qnet.save(&output, iteration)?;
+    println!("{} (iteration {}) -> {}", input, iteration, output);
+    Ok(())
+}
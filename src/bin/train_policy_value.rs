@@ -0,0 +1,30 @@
+use std::time::Instant;
+
+use cerke_dqn::learn::cerke::environment::ParallelCerke;
+use cerke_dqn::learn::cerke::network::NetworkSpec;
+use cerke_dqn::learn::cerke::optimizer::OptimizerConfig;
+use cerke_dqn::learn::cerke::policy_value::{PolicyValueAgent, PolicyValueConfig};
+
+fn main() {
+    // optional JSON network spec and optimizer config to train instead of the defaults
+    let mut config = PolicyValueConfig::default();
+    if let Some(path) = std::env::args().nth(1) {
+        config.network = NetworkSpec::load(&path).unwrap();
+    }
+    if let Some(path) = std::env::args().nth(2) {
+        config.optimizer = OptimizerConfig::load(&path).unwrap();
+    }
+    let mut agent = PolicyValueAgent::with_config(config);
+
+    let now = Instant::now();
+    for i in 0..10000 {
+        let mut env = ParallelCerke::new();
+        agent.iteration(&mut env);
+
+        let elapsed_time = now.elapsed();
+        println!("{} : {} sec", i + 1, elapsed_time.as_secs_f64());
+        if let Some(metrics) = agent.last_metrics() {
+            println!("{}", metrics.to_json());
+        }
+    }
+}
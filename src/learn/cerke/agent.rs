@@ -1,370 +1,517 @@
-use std::error::Error;
+use std::{
+    collections::{HashMap, VecDeque},
+    num::{NonZeroU64, NonZeroUsize},
+};
 
-use cetkaik_full_state_transition::{Config, message::{AfterHalfAcceptance, PureMove}, state::{self, Phase}};
+use cetkaik_full_state_transition::state::Phase;
 use chrono::Utc;
-use rand::{prelude::SliceRandom, thread_rng};
-use rand_distr::Distribution;
+use rand::{thread_rng, Rng};
 
-use super::{brain::QNet, environment::{Action, CerkeEnv}};
+use super::{brain::QNet, metrics::{IterationMetrics, TrainMetrics}, network::NetworkSpec, optimizer::OptimizerConfig, policy::Policy, replay::{self, EncodedPhase}, replay_stats::ReplayStats, service::{InferenceService, ServiceConfig}};
 use crate::learn::{
     cerke::{
         brain::Brain,
-        environment::{self, ActionResult, Environment},
-    },
-    memory::{Experience, Memory},
-    state_to_feature::{
-        afterhalf_candidates_to_mask, candidates_to_mask, get_after_half_candidate_by_index,
-        get_candidate_by_index, get_tymok_candidate_by_index, state_to_feature, tymok_mask,
-        ACTION_SIZE,
+        environment::{self, ActionResult},
     },
+    episode::{Episode, EpisodeMemory},
+    memory::{Batch, Eviction, Experience, Memory, Sampling, SharedMemory},
+    state_to_feature::{phase_to_mask, state_to_feature, ACTION_SIZE, STATE_SIZE},
 };
 
+#[derive(Clone, Copy, Debug)]
+pub enum TargetSync {
+    // copy the learning network into the target network every `every` train steps
+    Hard { every: NonZeroU64 },
+    // move the target network towards the learning network by `tau` every train step
+    Soft { tau: f64 },
+    // no separate target network: targets come from the learning network itself
+    None,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum EpisodeTargets {
+    // TD targets only
+    Off,
+    // moves the TD target of an experience played in a stored game by `weight` towards the
+    // lambda-return of its move in that game; `lambda` 1 is the Monte-Carlo return
+    Mixed { lambda: f32, weight: f32 },
+}
+
+#[derive(Clone, Debug)]
+pub struct AgentConfig {
+    pub gamma: f32,
+    // number of the player's own turns summed into one experience
+    pub n_step: usize,
+    pub replay_capacity: usize,
+    pub sampling: Sampling,
+    // number of whole games kept for inspection and Monte-Carlo returns
+    pub episode_capacity: NonZeroUsize,
+    pub episode_targets: EpisodeTargets,
+    pub target_sync: TargetSync,
+    // let the learning network pick the next action and the target network evaluate it
+    pub double_dqn: bool,
+    // train steps between two checkpoints
+    pub checkpoint_every: NonZeroU64,
+    pub network: NetworkSpec,
+    pub optimizer: OptimizerConfig,
+    // probability that a head of an ensemble learns from a given experience
+    pub bootstrap: f64,
+    // act on noisy weights; switched off for a deployed agent so that its play is deterministic
+    pub explore: bool,
+    // threads playing the games of an iteration once an inference service is attached
+    pub actors: usize,
+    // train steps between two weight updates of an attached inference service under soft or no
+    // target sync; hard sync updates it along with the target network
+    pub service_sync_every: NonZeroU64,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            gamma: 0.99,
+            n_step: 3,
+            replay_capacity: 2_000_000,
+            sampling: Sampling::Prioritized {
+                alpha: 0.6,
+                beta: 0.4,
+                epsilon: 0.01,
+            },
+            episode_capacity: NonZeroUsize::new(1000).unwrap(),
+            episode_targets: EpisodeTargets::Off,
+            target_sync: TargetSync::Hard {
+                every: NonZeroU64::new(10).unwrap(),
+            },
+            double_dqn: true,
+            checkpoint_every: NonZeroU64::new(10).unwrap(),
+            network: NetworkSpec::default(),
+            optimizer: OptimizerConfig::default(),
+            bootstrap: 0.5,
+            explore: true,
+            actors: 4,
+            service_sync_every: NonZeroU64::new(10).unwrap(),
+        }
+    }
+}
+
 pub struct CerkeAgent {
+    config: AgentConfig,
     qnet: QNet,
-    experience: Memory<Phase, usize>,
+    experience: SharedMemory<EncodedPhase, usize>,
+    episodes: EpisodeMemory<Phase, usize>,
+    // the returns each stored game gives its moves, by game, oldest first, and their sum and
+    // count by (state fingerprint, action) over the stored games
+    episode_moves: VecDeque<Vec<((u64, usize), f32)>>,
+    episode_returns: HashMap<(u64, usize), (f32, u32)>,
+    // train steps since the current iteration began
+    step_metrics: Vec<TrainMetrics>,
+    metrics: Vec<IterationMetrics>,
+    // acting goes through this service when attached
+    service: Option<InferenceService>,
     it: i64,
     name: String
 }
 
 impl CerkeAgent {
     pub fn new() -> Self {
+        Self::with_config(AgentConfig::default())
+    }
+
+    pub fn with_config(config: AgentConfig) -> Self {
+        let mut qnet = QNet::with_config(config.network.clone(), config.optimizer.clone());
+        qnet.share_target(matches!(config.target_sync, TargetSync::None));
         Self {
-            qnet: QNet::new(),
-            experience: Memory::new(),
+            experience: SharedMemory::new(Memory::with_capacity(
+                config.replay_capacity,
+                Eviction::Fifo,
+                config.sampling,
+            )),
+            episodes: EpisodeMemory::with_capacity(config.episode_capacity),
+            episode_moves: VecDeque::new(),
+            episode_returns: HashMap::new(),
+            qnet,
+            config,
+            step_metrics: Vec::new(),
+            metrics: Vec::new(),
+            service: None,
             it: 0,
             name: Utc::now().format("%Y%m%dT%H%M%S").to_string()
         }
     }
 
-    pub fn from_file(path: String) -> Self {
+    pub fn from_file(path: String) -> anyhow::Result<Self> {
         let mut qnet  = QNet::new();
-        qnet.load(&path);
-        Self {
+        let it = qnet.load(&path)?;
+        let config = AgentConfig {
+            network: qnet.spec().clone(),
+            optimizer: qnet.optimizer().clone(),
+            explore: false,
+            ..AgentConfig::default()
+        };
+        Ok(Self {
+            episodes: EpisodeMemory::with_capacity(config.episode_capacity),
+            episode_moves: VecDeque::new(),
+            episode_returns: HashMap::new(),
+            config,
             qnet,
-            experience: Memory::new(),
-            it: 0,
+            experience: SharedMemory::new(Memory::new()),
+            step_metrics: Vec::new(),
+            metrics: Vec::new(),
+            service: None,
+            it,
             name: Utc::now().format("%Y%m%dT%H%M%S").to_string()
-        }
+        })
     }
 
-    fn max_q_sction(&self, env: &Phase, inverted: bool) -> f32 {
-        let vec = state_to_feature(env);
-        let res = {
-            let mut batch = Vec::new();
-            batch.push(vec);
-            self.qnet
-                .forward(batch.iter().map(|x| x.as_slice()).collect())
-                .unwrap()[0]
-                .clone()
-        };
-        let mask = match env {
-            Phase::Start(state) => {
-                let (hop1zuo1_candidates, candidates) =
-                    state.get_candidates(Config::cerke_online_alpha());
-                candidates_to_mask(&hop1zuo1_candidates, &candidates)
-            }
-            Phase::AfterCiurl(state) => {
-                let candidates = state.get_candidates(Config::cerke_online_alpha());
-                afterhalf_candidates_to_mask(&candidates)
-            }
-            Phase::Moved(_state) => tymok_mask(),
-        };
+    // continues training from the checkpoint at `path`, whose network and optimizer replace
+    // `config.network` and `config.optimizer`
+    pub fn resume(path: String, config: AgentConfig) -> anyhow::Result<Self> {
+        let mut agent = Self::with_config(config);
+        agent.it = agent.qnet.load(&path)?;
+        agent.config.network = agent.qnet.spec().clone();
+        agent.config.optimizer = agent.qnet.optimizer().clone();
+        Ok(agent)
+    }
 
-        if inverted {
-            res.into_iter()
-                .enumerate()
-                .filter_map(|(i, x)| if mask[i] > 0 { Some(-x) } else { None })
-                .reduce(f32::min)
-                .unwrap()
-        } else {
-            res.into_iter()
-                .enumerate()
-                .filter_map(|(i, x)| if mask[i] > 0 { Some(x) } else { None })
-                .reduce(f32::max)
-                .unwrap()
-        }
+    pub fn config(&self) -> &AgentConfig {
+        &self.config
     }
 
-    fn select_move(&self, state: &state::A) -> Result<(PureMove, usize), Box<dyn Error>> {
-        let (hop1zuo1_candidates, candidates) = state.get_candidates(Config::cerke_online_alpha());
-        let mask = candidates_to_mask(&hop1zuo1_candidates, &candidates);
-        let state_vec = state_to_feature(&Phase::Start(state.clone()));
+    // one head drawn for each of `games` games for Thompson-style exploration while training
+    // an ensemble; `None` when there is nothing to draw from
+    pub fn thompson_heads(&self, games: usize) -> Option<Vec<usize>> {
+        let heads = self.qnet.heads();
+        if heads == 1 || !self.config.explore {
+            return None;
+        }
+        let mut rng = thread_rng();
+        Some((0..games).map(|_| rng.gen_range(0..heads)).collect())
+    }
 
-        let res = self
-            .qnet
-            .forward(vec![&state_vec[..]])
-            .unwrap()
-            .pop()
-            .unwrap();
+    // moves acting onto an inference service serving a copy of the target network, which
+    // follows the target updates; the returned handle lets other threads act with it too
+    pub fn attach_service(&mut self, config: ServiceConfig) -> anyhow::Result<InferenceService> {
+        let spec = self.qnet.spec().clone();
+        let vars = self.qnet.target_variables();
+        let service = InferenceService::spawn(
+            move || {
+                let mut qnet = QNet::with_spec(spec);
+                qnet.set_target_variables(&vars)?;
+                Ok(qnet)
+            },
+            config,
+        )?;
+        self.service = Some(service.clone());
+        Ok(service)
+    }
 
-        /*
-        // epsilon - greedy 
+    pub fn service(&self) -> Option<&InferenceService> {
+        self.service.as_ref()
+    }
 
-        let mut max_value = f32::NEG_INFINITY;
-        let mut max_index = 0;
+    // row-major legal-action masks of `states`
+    fn legal_masks<'a>(states: impl Iterator<Item = &'a EncodedPhase>) -> Vec<i8> {
+        let mut masks = Vec::new();
+        for state in states {
+            let offset = masks.len();
+            masks.resize(offset + ACTION_SIZE, 0);
+            for i in state.legal_actions() {
+                masks[offset + i] = 1;
+            }
+        }
+        masks
+    }
 
+    // row-major target distributions of the batch, one per head: `reward + gamma^steps * Z(s', a*)`
+    // projected onto what the network predicts, or the plain n-step target for an expected-value
+    // network
+    fn targets(&self, batch: &Batch<EncodedPhase, usize>) -> Vec<f32> {
+        let gamma = self.config.gamma;
+        let returns = self.qnet.returns();
+        let next_legal = Self::legal_masks(batch.experiences.iter().map(|x| &x.next_state));
+        let next = self
+            .qnet
+            .greedy_distributions(
+                batch.next_states.chunks(STATE_SIZE).collect(),
+                next_legal.chunks(ACTION_SIZE).collect(),
+                self.config.double_dqn,
+            )
+            .unwrap();
 
-        if rand::random::<f32>() < 0.98f32 {
-            for (i, v) in res.iter().enumerate() {
-                if mask[i] == 1 && max_value < *v {
-                    max_index = i;
-                    max_value = *v;
+        // any distribution does once the discount is 0
+        let heads = self.qnet.heads();
+        let terminal = vec![1.0 / returns.atoms() as f32; returns.atoms()];
+        let mut targets = Vec::with_capacity(batch.len() * heads * returns.atoms());
+        for (experience, next) in batch.experiences.iter().zip(next) {
+            let start = targets.len();
+            match next {
+                // each head bootstraps from its own greedy action
+                Some(next) if !experience.done => {
+                    let discount = gamma.powi(experience.steps as i32);
+                    for (_, next) in next {
+                        targets.extend(returns.project(experience.value, discount, &next));
+                    }
+                }
+                _ => {
+                    let target = returns.project(experience.value, 0f32, &terminal);
+                    for _ in 0..heads {
+                        targets.extend_from_slice(&target);
+                    }
                 }
             }
-        } else {
-            let mut candidates = Vec::new();
-            for (i, _v) in res.iter().enumerate() {
-                if mask[i] == 1 {
-                    candidates.push(i);
+            if let Some((weight, g)) = self.episode_return(experience) {
+                let target = returns.project(g, 0f32, &terminal);
+                for (x, y) in targets[start..].iter_mut().zip(target.iter().cycle()) {
+                    *x = (1f32 - weight) * *x + weight * y;
                 }
             }
-            max_index = *candidates.choose(&mut thread_rng()).unwrap();
         }
-        */
-        
-        let beta = 2.0f32;
-        let items: Vec<f32> = res.iter().enumerate().map(|(i,x)| {
-            if mask[i] == 1 {
-                f32::exp(beta * x)
-            } else {
-                0f32
-            }
-        }).collect();
-        let wei = rand::distributions::WeightedIndex::new(items).unwrap();
-        let mut rng = thread_rng();
-        let max_index = wei.sample(&mut rng);
+        targets
+    }
 
-        Ok((
-            get_candidate_by_index(max_index, &hop1zuo1_candidates, &candidates),
-            max_index,
-        ))
+    // `EpisodeTargets::Mixed` weight and the mean return of the move of `experience` over the
+    // stored games that played it
+    fn episode_return(&self, experience: &Experience<EncodedPhase, usize>) -> Option<(f32, f32)> {
+        match self.config.episode_targets {
+            EpisodeTargets::Off => None,
+            EpisodeTargets::Mixed { weight, .. } => self
+                .episode_returns
+                .get(&(experience.current_state.fingerprint(), experience.action))
+                .map(|(sum, count)| (weight, sum / *count as f32)),
+        }
     }
 
-    fn select_stepped(
+    // lambda-returns of every move of `episode`, bootstrapping from the greedy values of the
+    // target network
+    fn move_returns(
         &self,
-        state: &state::C,
-    ) -> Result<(AfterHalfAcceptance, usize), Box<dyn Error>> {
-        let candidates = state.get_candidates(Config::cerke_online_alpha());
-        let mask = afterhalf_candidates_to_mask(&candidates);
-        let state_vec = state_to_feature(&Phase::AfterCiurl(state.clone()));
+        episode: &Episode<Phase, usize>,
+        lambda: f32,
+    ) -> Vec<((u64, usize), f32)> {
+        let gamma = self.config.gamma;
+        let mut moves = Vec::with_capacity(episode.len());
+        for side in 0..2 {
+            let steps = &episode.sides[side];
+            if steps.is_empty() {
+                continue;
+            }
+            let states: Vec<EncodedPhase> =
+                steps.iter().map(|step| EncodedPhase::encode(&step.state)).collect();
+            let returns = if lambda >= 1f32 {
+                episode.returns(side, gamma)
+            } else {
+                let features: Vec<_> = states.iter().map(|x| x.feature()).collect();
+                let masks: Vec<_> = states.iter().map(|x| x.mask()).collect();
+                let q = self
+                    .qnet
+                    .forward(
+                        features.iter().map(|x| &x[..]).collect(),
+                        masks.iter().map(|x| &x[..]).collect(),
+                    )
+                    .unwrap();
+                let values: Vec<f32> = states
+                    .iter()
+                    .zip(q)
+                    .map(|(state, q)| {
+                        state.legal_actions().map(|a| q[a]).fold(f32::NEG_INFINITY, f32::max)
+                    })
+                    .map(|v| if v.is_finite() { v } else { 0f32 })
+                    .collect();
+                episode.lambda_returns(side, gamma, lambda, &values)
+            };
+            for ((state, step), g) in states.iter().zip(steps).zip(returns) {
+                moves.push(((state.fingerprint(), step.action), g));
+            }
+        }
+        moves
+    }
 
-        let res = self
-            .qnet
-            .forward(vec![&state_vec[..]])
+    // predicted return distribution of `action` in `state`: probabilities over
+    // `Returns::support` or quantiles, depending on the network
+    pub fn return_distribution(&self, state: &Phase, action: usize) -> Vec<f32> {
+        let state_vec = state_to_feature(state);
+        let mask = phase_to_mask(state);
+        self.qnet
+            .forward_distribution(vec![&state_vec[..]], vec![&mask[..]], &[action])
             .unwrap()
             .pop()
-            .unwrap();
+            .unwrap()
+    }
+
+    pub fn put_memory(&mut self, ex: Experience<Phase, usize>) { 
+        self.experience.put(ex.map_state(EncodedPhase::encode))
+    }
 
-        let mut max_value = f32::NEG_INFINITY;
-        let mut max_index = 0;
+    // a handle actor threads can put experiences into
+    pub fn memory(&self) -> SharedMemory<EncodedPhase, usize> {
+        self.experience.clone()
+    }
 
-        if rand::random::<f32>() < 0.98f32 {
-            for (i, v) in res.iter().enumerate() {
-                if mask[i] == 1 && max_value < *v {
-                    max_index = i;
-                    max_value = *v;
+    pub fn put_episodes(&mut self, episodes: Vec<Episode<Phase, usize>>) {
+        for episode in episodes {
+            if let EpisodeTargets::Mixed { lambda, .. } = self.config.episode_targets {
+                let moves = self.move_returns(&episode, lambda);
+                for (key, g) in moves.iter() {
+                    let entry = self.episode_returns.entry(*key).or_insert((0f32, 0));
+                    entry.0 += g;
+                    entry.1 += 1;
                 }
+                self.episode_moves.push_back(moves);
             }
-        } else {
-            let mut candidates = Vec::new();
-            for (i, _v) in res.iter().enumerate() {
-                if mask[i] == 1 {
-                    candidates.push(i);
+            if self.episodes.put(episode).is_some() {
+                for (key, g) in self.episode_moves.pop_front().unwrap_or_default() {
+                    if let Some(entry) = self.episode_returns.get_mut(&key) {
+                        entry.0 -= g;
+                        entry.1 -= 1;
+                        if entry.1 == 0 {
+                            self.episode_returns.remove(&key);
+                        }
+                    }
                 }
             }
-            max_index = *candidates.choose(&mut thread_rng()).unwrap();
         }
+    }
 
-        Ok((
-            get_after_half_candidate_by_index(max_index, &candidates),
-            max_index,
-        ))
+    pub fn episodes(&self) -> &EpisodeMemory<Phase, usize> {
+        &self.episodes
     }
 
-    fn select_tymok(
-        &self,
-        state: &state::HandNotResolved,
-    ) -> Result<(bool, usize), Box<dyn Error>> {
-        let mask = tymok_mask();
-        let state_vec = state_to_feature(&Phase::Moved(state.clone()));
+    pub fn memory_stats(&self) -> ReplayStats {
+        ReplayStats::of(&self.experience.lock())
+    }
 
-        let res = self
-            .qnet
-            .forward(vec![&state_vec[..]])
-            .unwrap()
-            .pop()
-            .unwrap();
+    // aggregates the train steps since the last call into the metrics of one iteration
+    pub fn finish_iteration(&mut self) -> &IterationMetrics {
+        let metrics = IterationMetrics::of(self.it, &self.step_metrics);
+        self.step_metrics.clear();
+        self.metrics.push(metrics);
+        self.metrics.last().unwrap()
+    }
 
-        let mut max_value = f32::NEG_INFINITY;
-        let mut max_index = 0;
+    // one entry per finished iteration, oldest first
+    pub fn metrics(&self) -> &[IterationMetrics] {
+        &self.metrics
+    }
 
-        if rand::random::<f32>() < 0.98f32 {
-            for (i, v) in res.iter().enumerate() {
-                if mask[i] == 1 && max_value < *v {
-                    max_index = i;
-                    max_value = *v;
-                }
-            }
-        } else {
-            let mut candidates = Vec::new();
-            for (i, _v) in res.iter().enumerate() {
-                if mask[i] == 1 {
-                    candidates.push(i);
-                }
-            }
-            max_index = *candidates.choose(&mut thread_rng()).unwrap();
-        }
+    pub fn last_metrics(&self) -> Option<&IterationMetrics> {
+        self.metrics.last()
+    }
 
-        Ok((get_tymok_candidate_by_index(max_index), max_index))
+    // writes the network for `CpuNet::load`, to play without libtorch
+    pub fn export_cpu(&self, path: &str) -> anyhow::Result<()> {
+        self.qnet.export(self.it)?.save(path)
     }
 
-    pub fn select_action(&self, state: &Phase) -> (Action, usize) {
-        match state {
-            Phase::Start(state) => {
-                let (mov, index) = self.select_move(state).unwrap();
-                (Action::Pure(mov), index)
-            }
-            Phase::AfterCiurl(state) => {
-                let (mov, index) = self.select_stepped(state).unwrap();
-                (Action::AfterHalf(mov), index)
-            }
-            Phase::Moved(state) => {
-                let (mov, index) = self.select_tymok(state).unwrap();
-                (Action::IsTymok(mov), index)
-            }
+    // writes the learning network as TorchScript for tools outside this crate
+    pub fn export_torchscript(&self, path: &str) -> anyhow::Result<()> {
+        self.qnet.export_torchscript(path, self.it)
+    }
+
+    pub fn save_memory(&self, path: &str) -> anyhow::Result<()> {
+        replay::save_memory(&self.experience.lock(), path)
+    }
+
+    pub fn load_memory(&mut self, path: &str) -> anyhow::Result<()> {
+        replay::load_memory(&mut self.experience.lock(), path)
+    }
+
+    fn update_service(&self) {
+        if let Some(service) = &self.service {
+            service
+                .update(self.qnet.target_variables())
+                .expect("cannot update the inference service");
         }
     }
 
-    pub fn parallel_select_action (&self, states: &Vec<Phase> ) -> Vec< (Action, usize) > {
-        enum Candidates {
-            Start(Vec<PureMove>,Vec<PureMove>),
-            AfterCiurl(Vec<AfterHalfAcceptance>),
-            Moved
+    pub fn train(&mut self) {         
+        // actors keep putting while the network trains, so the lock is only held while the
+        // batch is copied out; the targets are computed after it is released
+        let batch = self.experience.lock().sample_batch(1000);
+        let targets = self.targets(&batch);
+        let legal = Self::legal_masks(batch.experiences.iter().map(|x| &x.current_state));
+        let actions: Vec<usize> = batch.experiences.iter().map(|x| x.action).collect();
+        let masks = bootstrap_masks(&batch.stamps, self.qnet.heads(), self.config.bootstrap);
+        let (indices, states, weights) = (batch.indices, batch.states, batch.weights);
+
+        let (td_errors, metrics) = self.qnet
+            .train(&states, &legal, &actions, &targets, &weights, &masks)
+            .expect("Train Failed");
+        self.step_metrics.push(metrics);
+        // slots overwritten in the meantime just get a slightly stale priority
+        let mut memory = self.experience.lock();
+        for (index, td_error) in indices.into_iter().zip(td_errors) {
+            memory.update_priority(index, td_error);
         }
+        drop(memory);
 
-        let mut vecs = Vec::new();
-        let mut masks = Vec::new();
-        let mut candidates_vec = Vec::new();
-
-        for state in states.iter() {
-            vecs.push( state_to_feature(state) );
-            masks.push(
-                match state {
-                Phase::Start(state) => {
-                    let (hop1zuo1_candidates, candidates) = state.get_candidates(Config::cerke_online_alpha());
-                    let res = candidates_to_mask(&hop1zuo1_candidates, &candidates);
-                    candidates_vec.push(Candidates::Start(hop1zuo1_candidates, candidates));
-                    res
-                },
-                Phase::AfterCiurl(state) => {
-                    let candidates = state.get_candidates(Config::cerke_online_alpha());
-                    let res = afterhalf_candidates_to_mask(&candidates);
-                    candidates_vec.push(Candidates::AfterCiurl(candidates));
-                    res
-                },
-                Phase::Moved(_) => {
-                    candidates_vec.push(Candidates::Moved);
-                    tymok_mask()
+        self.it += 1;
+        match self.config.target_sync {
+            TargetSync::Hard { every } => {
+                if self.it as u64 % every.get() == 0 {
+                    self.qnet.update_hard();
+                    self.update_service();
                 }
-            })
-        }
-        let raw_res =self
-            .qnet
-            .forward(vecs.iter().map(|x| x.as_slice()).collect::<Vec<&[f32]>>()).unwrap();
-        
-        let mut result = Vec::new();
-        for (i, (res, candidates)) in raw_res.into_iter().zip(candidates_vec).enumerate() {
-            let mask = masks[i];
-
-            let mut max_value = f32::NEG_INFINITY;
-            let mut max_index = 0;
-
-            if rand::random::<f32>() < 0.98f32 {
-                for (i, v) in res.iter().enumerate() {
-                    if mask[i] == 1 && max_value < *v {
-                        max_index = i;
-                        max_value = *v;
-                    }
+            }
+            TargetSync::Soft { tau } => {
+                self.qnet.update_soft(tau);
+                if self.it as u64 % self.config.service_sync_every.get() == 0 {
+                    self.update_service();
                 }
-            } else {
-                let mut candidates = Vec::new();
-                for (i, _v) in res.iter().enumerate() {
-                    if mask[i] == 1 {
-                        candidates.push(i);
-                    }
+            }
+            TargetSync::None => {
+                if self.it as u64 % self.config.service_sync_every.get() == 0 {
+                    self.update_service();
                 }
-                max_index = *candidates.choose(&mut thread_rng()).unwrap();
             }
-            
-            result.push( match candidates {
-                Candidates::Start(hop1zuo1_candidates, candidates) => { 
-                    (
-                        Action::Pure(get_candidate_by_index(max_index, &hop1zuo1_candidates, &candidates)),
-                        max_index,
-                    )
-                },
-                Candidates::AfterCiurl(candidates) => {
-                    (
-                        Action::AfterHalf(get_after_half_candidate_by_index(max_index, &candidates)),
-                        max_index,
-                    )
-                },
-                Candidates::Moved => {
-                    (
-                        Action::IsTymok(get_tymok_candidate_by_index(max_index)),
-                        max_index
-                    )
-                },
-            })
-        } 
-
-        result
+        }
+        if self.it as u64 % self.config.checkpoint_every.get() == 0 {
+            let path = format!("./result/{}", self.name);
+            self.qnet.save(&path, self.it).expect("cannot save the checkpoint");
+        }
     }
+}
 
-    pub fn select_para (&self, environments: Vec<CerkeEnv>) -> Vec<(Action, usize)> {
-        let states: Vec<Phase> = environments.iter().map(|environment| environment.observe()).collect();
-        self.parallel_select_action(&states)
+impl Policy for CerkeAgent {
+    // Q-values to act on: noisy networks perturb their weights to explore unless `explore` is off
+    fn acting_q(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<f32>> {
+        let explore = self.noisy() && self.config.explore;
+        match &self.service {
+            Some(service) => service.forward(batch, legal, explore).unwrap(),
+            None if explore => self.qnet.forward_explore(batch, legal).unwrap(),
+            None => self.qnet.forward(batch, legal).unwrap(),
+        }
     }
 
-    pub fn put_memory(&mut self, ex: Experience<Phase, usize>) { 
-        self.experience.put(ex)
+    fn noisy(&self) -> bool {
+        self.qnet.spec().noisy.is_some()
     }
-    pub fn train(&mut self) {         
-        let mut update_batch = Vec::new();
-        let gamma = 0.99f32;
-
-        for _i in 0..1000 {
-            let Experience {
-                current_state,
-                action,
-                next_state,
-                value,
-            } = self.experience.sample();
-
-            let max_q = self.max_q_sction(next_state, false );
- 
-            let new_q = value + gamma * max_q;
-            let mut new_q_one_hot = [0f32; ACTION_SIZE];
-            let mut mask_one_hot = [0f32; ACTION_SIZE];
-            new_q_one_hot[*action] = new_q;
-            mask_one_hot[*action] = 1f32;
-
-            update_batch.push((state_to_feature(current_state), new_q_one_hot, mask_one_hot))
-        }
-        self.qnet
-            .train(
-                update_batch
-                    .iter()
-                    .map(|(x, y, m)| (x.as_slice(), y.as_slice(), m.as_slice()))
-                    .collect(),
-            )
-            .expect("Train Failed");
 
-        self.it += 1;
-        if self.it % 10 == 9 {
-            self.qnet.update_hard();
-            let path = format!("./result/{}", self.name);
-            self.qnet.save(&path);
+    fn head_q(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<Vec<f32>>> {
+        match &self.service {
+            Some(service) => service.forward_heads(batch, legal).unwrap(),
+            None => self.qnet.forward_heads(batch, legal).unwrap(),
         }
     }
 }
+
+// 0/1 bootstrap masks of experiences over `heads` heads, `heads` values per experience. a mask
+// is a hash of the put order of the experience, so a head keeps seeing the same experiences
+fn bootstrap_masks(stamps: &[u64], heads: usize, p: f64) -> Vec<f32> {
+    if heads == 1 {
+        return vec![1f32; stamps.len()];
+    }
+    stamps
+        .iter()
+        .flat_map(|stamp| {
+            (0..heads as u64).map(move |head| {
+                // splitmix64 finaliser
+                let mut z = stamp.wrapping_mul(heads as u64).wrapping_add(head);
+                z = z.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                z ^= z >> 31;
+                let u = (z >> 11) as f64 / (1u64 << 53) as f64;
+                (u < p) as i32 as f32
+            })
+        })
+        .collect()
+}
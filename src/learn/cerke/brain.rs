@@ -1,156 +1,664 @@
-use anyhow::Result;
+use std::{collections::HashMap, path::Path};
 
-use tch::{
-    nn,
-    nn::OptimizerConfig,
-    nn::{ModuleT, Optimizer, VarStore},
-    Device, Tensor,
-};
+use anyhow::{bail, ensure, Context, Result};
 
-use crate::learn::state_to_feature::{ACTION_SIZE, STATE_SIZE};
+use tch::{nn, nn::VarStore, Device, Kind, Tensor};
 
-fn network(vs: &nn::Path) -> impl ModuleT {
-    nn::seq_t()
-        .add(nn::linear(
-            vs / "layer1",
-            STATE_SIZE as i64,
-            512,
-            Default::default(),
-        ))
-        .add(nn::batch_norm1d(vs, 512, Default::default()))
-        .add_fn(|xs| xs.relu())
-        .add(nn::linear(vs, 512, 512, Default::default()))
-        .add_fn(|xs| xs.relu())
-        .add(nn::linear(vs, 512, ACTION_SIZE as i64, Default::default()))
-}
+use super::{
+    checkpoint::{self, CheckpointMeta, Model},
+    inference::CpuNet,
+    layers::{q_module, QModule},
+    metrics::TrainMetrics,
+    network::{NetworkSpec, Returns},
+    optimizer::{Optimizer, OptimizerConfig},
+    torchscript,
+};
+use crate::learn::state_to_feature::{ACTION_SIZE, STATE_SIZE};
 
-pub trait Brain {
-    fn train(&mut self, batch: Vec<(&[f32], &[f32], &[f32])>) -> Result<()>;
-    fn forward(&self, batch: Vec<&[f32]>) -> Result<Vec<Vec<f32>>>;
-    fn update_hard(&mut self);
-    fn update_soft(&mut self, tau: f64);
-    fn save(&self, name: &String);
-    fn load(&mut self, name: &String);
-}
+// the trait lives apart from QNet so that builds without libtorch have it
+pub use super::learner::Brain;
 
 pub struct QNet {
+    spec: NetworkSpec,
     device: Device,
     vs_learn: VarStore,
     vs_target: VarStore,
-    net_learn: Box<dyn ModuleT>,
-    net_target: Box<dyn ModuleT>,
+    net_learn: Box<dyn QModule>,
+    net_target: Box<dyn QModule>,
+    // when set, the learning network stands in for the target network, which is left unused
+    shared_target: bool,
     opt: Optimizer,
 }
 
 impl QNet {
     pub fn new() -> Self {
+        Self::with_spec(NetworkSpec::default())
+    }
+
+    pub fn with_spec(spec: NetworkSpec) -> Self {
+        Self::with_config(spec, OptimizerConfig::default())
+    }
+
+    pub fn with_config(spec: NetworkSpec, optimizer: OptimizerConfig) -> Self {
         let device = Device::cuda_if_available();
 
         let vs_learn = nn::VarStore::new(device);
-        let net_learn = Box::new(network(&vs_learn.root()));
+        let net_learn = q_module(&vs_learn.root(), &spec);
 
         let vs_target = nn::VarStore::new(device);
-        let net_target = Box::new(network(&vs_target.root()));
+        let net_target = q_module(&vs_target.root(), &spec);
 
-        let opt = nn::Adam::default().build(&vs_learn, 0.00025).unwrap();
+        let opt = Optimizer::new(&vs_learn, optimizer);
         Self {
+            spec,
             device,
             vs_learn,
             net_learn,
             vs_target,
             net_target,
+            shared_target: false,
             opt,
         }
     }
+
+    pub fn spec(&self) -> &NetworkSpec {
+        &self.spec
+    }
+
+    pub fn optimizer(&self) -> &OptimizerConfig {
+        self.opt.config()
+    }
+
+    // makes the learning network compute the targets and answer `forward` itself
+    pub fn share_target(&mut self, shared: bool) {
+        self.shared_target = shared;
+    }
+
+    fn target_net(&self) -> &dyn QModule {
+        if self.shared_target {
+            &*self.net_learn
+        } else {
+            &*self.net_target
+        }
+    }
+
+    fn target_vs(&self) -> &VarStore {
+        if self.shared_target {
+            &self.vs_learn
+        } else {
+            &self.vs_target
+        }
+    }
+
+    // a fresh network of `spec` keeping the optimizer config and the target mode
+    fn rebuild(&mut self, spec: NetworkSpec) {
+        let shared_target = self.shared_target;
+        *self = Self::with_config(spec, self.opt.config().clone());
+        self.shared_target = shared_target;
+    }
+
+    // deep copy of the target network's weights, safe to hand to another thread
+    pub fn target_variables(&self) -> HashMap<String, Tensor> {
+        tch::no_grad(|| {
+            self.target_vs()
+                .variables()
+                .into_iter()
+                .map(|(name, var)| (name, var.copy()))
+                .collect()
+        })
+    }
+
+    // overwrites the target network with weights from `target_variables` of a QNet of the same spec
+    pub fn set_target_variables(&mut self, vars: &HashMap<String, Tensor>) -> Result<()> {
+        copy_variables(self.target_vs(), vars)
+    }
+
+    // restores the baseline network saved before checkpoints had a `meta.json`, as the
+    // default spec with a fresh optimizer
+    fn load_legacy(&mut self, name: &str) -> Result<i64> {
+        self.rebuild(NetworkSpec::default());
+        copy_variables(&self.vs_learn, &legacy_variables(&legacy_file(name, "learn"))?)?;
+        copy_variables(&self.vs_target, &legacy_variables(&legacy_file(name, "target"))?)?;
+        Ok(0)
+    }
+
+    // the target network, which `forward` evaluates, as a pure-Rust CPU network
+    pub fn export(&self, iteration: i64) -> Result<CpuNet> {
+        let vars = self
+            .target_vs()
+            .variables()
+            .into_iter()
+            .map(|(name, var)| {
+                let values = Vec::<f32>::from(var.to_device(Device::Cpu).to_kind(Kind::Float).flatten(0, -1));
+                (name, values)
+            })
+            .collect();
+        CpuNet::from_vars(self.spec.clone(), &vars, iteration)
+    }
+
+    // the learning network as a TorchScript module taking `(states, legal)` and returning the
+    // expected values and the return distributions of every action. it is traced on a CPU copy
+    // of the weights, so the module loads on machines without CUDA
+    pub fn export_torchscript(&self, path: &str, iteration: i64) -> Result<()> {
+        let vs = VarStore::new(Device::Cpu);
+        let net = q_module(&vs.root(), &self.spec);
+        copy_variables(&vs, &self.vs_learn.variables())?;
+        torchscript::export(&*net, &self.spec, path, iteration)
+    }
+
+    fn input_tensor(&self, batch: Vec<&[f32]>) -> Tensor {
+        Tensor::of_slice(&batch.concat())
+            .reshape(&[batch.len() as i64, STATE_SIZE as i64])
+            .to(self.device)
+    }
+
+    fn legal_tensor(&self, legal: &[i8]) -> Tensor {
+        Tensor::of_slice(legal)
+            .reshape(&[-1, ACTION_SIZE as i64])
+            .to_kind(Kind::Float)
+            .to(self.device)
+    }
 }
 
 impl Brain for QNet {
     #[must_use]
-    fn train(&mut self, batch: Vec<(&[f32], &[f32], &[f32])>) -> Result<()> {
+    fn train(
+        &mut self,
+        states: &[f32],
+        legal: &[i8],
+        actions: &[usize],
+        targets: &[f32],
+        weights: &[f32],
+        masks: &[f32],
+    ) -> Result<(Vec<f32>, TrainMetrics)> {
         let dev = self.device;
+        let legal_tensor = self.legal_tensor(legal);
         let net = &self.net_learn;
-        let (batch_size, channels, output_channels) =
-            (batch.len(), batch[0].0.len(), batch[0].1.len());
-
-        let mut input = Vec::with_capacity(batch_size * channels);
-        let mut output = Vec::with_capacity(batch_size * output_channels);
-        let mut mask = Vec::with_capacity(batch_size * output_channels);
+        let batch_size = actions.len() as i64;
 
-        for i in 0..batch_size {
-            let _ii = i * channels;
+        let input_tensor = Tensor::of_slice(states)
+            .reshape(&[batch_size, STATE_SIZE as i64])
+            .to(dev);
+        let atoms = self.spec.returns.atoms() as i64;
+        // each head of an ensemble learns from the batch as rows of its own
+        let heads = self.spec.ensemble as i64;
+        let rows = batch_size * heads;
+        let action_tensor = Tensor::of_slice(&actions.iter().map(|x| *x as i64).collect::<Vec<_>>())
+            .view([batch_size, 1, 1, 1])
+            .expand(&[batch_size, heads, 1, atoms], false)
+            .to(dev);
+        let target_tensor = Tensor::of_slice(targets).view([rows, atoms]).to(dev);
+        let weight_tensor = Tensor::of_slice(weights).to(dev);
+        let mask_tensor = Tensor::of_slice(masks).view([batch_size, heads]).to(dev);
 
-            let batch_item = batch[i].0;
-            for j in 0..channels {
-                input.push(batch_item[j]);
+        let res = net
+            .forward_heads(&input_tensor, &legal_tensor, true, true)
+            .gather(2, &action_tensor, false)
+            .reshape(&[rows, atoms]);
+        let q = self
+            .spec
+            .returns
+            .expected(&res.view([batch_size, heads, atoms]).mean_dim(&[1], true, Kind::Float).detach())
+            .squeeze_dim(1);
+        let (loss, td_errors) = match self.spec.returns {
+            Returns::Expected => {
+                let res = res.squeeze_dim(1);
+                let target_tensor = target_tensor.squeeze_dim(1);
+                let loss = Tensor::huber_loss(&res, &target_tensor, tch::Reduction::None, 1.0f64);
+                (loss, &target_tensor - &res)
             }
-
-            let batch_output_item = batch[i].1;
-            for j in 0..output_channels {
-                output.push(batch_output_item[j]);
+            Returns::Categorical { .. } => {
+                // cross-entropy against the projected target distribution
+                let loss = -(&target_tensor * res.log_softmax(-1, Kind::Float))
+                    .sum_dim_intlist(&[1], false, Kind::Float);
+                let td_errors = loss.shallow_clone();
+                (loss, td_errors)
             }
-
-            let _batch_mask_item = batch[i].2;
-            for j in 0..output_channels {
-                mask.push(batch_output_item[j]);
+            Returns::Quantile { .. } => {
+                // quantile Huber loss of every predicted quantile against every target sample
+                let shape = [rows, atoms, atoms];
+                let pred = res.unsqueeze(2).expand(&shape, false);
+                let target = target_tensor.unsqueeze(1).expand(&shape, false);
+                let huber = Tensor::huber_loss(&pred, &target, tch::Reduction::None, 1.0f64);
+                let tau = (Tensor::arange(atoms, (Kind::Float, dev)) * 2.0 + 1.0) / (2 * atoms) as f64;
+                let below = (&target - &pred).lt(0.0).to_kind(Kind::Float);
+                let loss = ((tau.view([1, atoms, 1]) - below).abs() * huber)
+                    .mean_dim(&[2], false, Kind::Float)
+                    .sum_dim_intlist(&[1], false, Kind::Float);
+                let td_errors = target_tensor.mean_dim(&[1], false, Kind::Float)
+                    - res.mean_dim(&[1], false, Kind::Float);
+                (loss, td_errors)
             }
-        }
-        let input_tensor = Tensor::of_slice(&input)
-            .reshape(&[batch_size as i64, channels as i64])
-            .to(dev);
-        let output_tensor = Tensor::of_slice(&output)
-            .reshape(&[batch_size as i64, output_channels as i64])
-            .to(dev);
-        let mask_tensor = Tensor::of_slice(&mask)
-            .reshape(&[batch_size as i64, output_channels as i64])
-            .to(dev);
+        };
+        // averaged over the heads learning from a row; a row none of them learns from keeps the
+        // average over every head as its priority
+        let active = mask_tensor.sum_dim_intlist(&[1], false, Kind::Float);
+        let td_errors = td_errors.detach().view([batch_size, heads]);
+        let td_errors = ((&td_errors * &mask_tensor).sum_dim_intlist(&[1], false, Kind::Float)
+            / active.clamp_min(1.0))
+        .where_self(&active.gt(0.0), &td_errors.mean_dim(&[1], false, Kind::Float));
+        let loss = (loss.view([batch_size, heads]) * &mask_tensor).sum_dim_intlist(&[1], false, Kind::Float)
+            / active.clamp_min(1.0);
+        let loss = (loss * weight_tensor).sum(Kind::Float);
 
-        let res = net.forward_t(&input_tensor, true);
-        let res = &mask_tensor * res;
-        let loss = Tensor::huber_loss(&res, &output_tensor, tch::Reduction::Sum, 1.0f64);
+        let loss = match self.opt.l1_penalty() {
+            Some(penalty) => loss + penalty,
+            None => loss,
+        };
 
-        //            let regularization = self.vs.trainable_variables().iter().map(|x| x.abs().sum(Kind::Float)).reduce(|x,y| x + y ).unwrap();
-        //            let loss = loss + regularization * Scalar::float(1e-7f64);
+        let lr = self.opt.lr();
+        let grad_norm = self.opt.backward_step(&loss);
+        let abs_td = td_errors.abs();
+        // the scalars and the TD errors come back from the device in one copy
+        let scalars = Tensor::stack(
+            &[
+                loss.detach(),
+                q.mean(Kind::Float),
+                q.max(),
+                abs_td.mean(Kind::Float),
+                abs_td.std(false),
+                abs_td.max(),
+            ],
+            0,
+        );
+        let host = Vec::<f32>::from(Tensor::cat(&[scalars, td_errors], 0).to_device(Device::Cpu));
+        let (scalars, td_errors) = host.split_at(6);
+        let metrics = TrainMetrics {
+            loss: scalars[0] as f64,
+            mean_q: scalars[1] as f64,
+            max_q: scalars[2] as f64,
+            td_error_mean: scalars[3] as f64,
+            td_error_std: scalars[4] as f64,
+            td_error_max: scalars[5] as f64,
+            grad_norm,
+            lr,
+            batch_size: actions.len(),
+        };
+        Ok((td_errors.to_vec(), metrics))
+    }
 
-        self.opt.backward_step(&loss);
-        println!("{}", f64::from(&loss));
-        Ok(())
+    #[must_use]
+    fn forward(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<Vec<Vec<f32>>> {
+        let input_tensor = self.input_tensor(batch);
+        let legal_tensor = self.legal_tensor(&legal.concat());
+        let result = self.target_net().forward_q(&input_tensor, &legal_tensor, false, false);
+        Ok(self.spec.returns.expected(&result).into())
     }
 
     #[must_use]
-    fn forward(&self, batch: Vec<&[f32]>) -> Result<Vec<Vec<f32>>> {
-        let dev = self.device;
-        let net = &self.net_target;
-        let (batch_size, channels) = (batch.len(), batch[0].len());
-
-        let mut input = Vec::with_capacity(batch_size * channels);
-        for i in 0..batch_size {
-            let _ii = i * channels;
-            let batch_item = batch[i];
-            for j in 0..channels {
-                input.push(batch_item[j]);
-            }
-        }
-        let input_tensor = Tensor::of_slice(&input).reshape(&[batch_size as i64, channels as i64]);
+    fn greedy_distributions(
+        &self,
+        batch: Vec<&[f32]>,
+        legal: Vec<&[i8]>,
+        double: bool,
+    ) -> Result<Vec<Option<Vec<(usize, Vec<f32>)>>>> {
+        let batch_size = batch.len() as i64;
+        let (heads, atoms) = (self.spec.ensemble as i64, self.spec.returns.atoms() as i64);
+        let any_legal: Vec<bool> = legal.iter().map(|legal| legal.iter().any(|x| *x != 0)).collect();
+        let input_tensor = self.input_tensor(batch);
+        let legal_tensor = self.legal_tensor(&legal.concat());
+
+        let returns = self.spec.returns;
+        let (actions, distributions) = tch::no_grad(|| {
+            let expected = |raw: &Tensor| {
+                returns
+                    .expected(&raw.view([batch_size * heads, ACTION_SIZE as i64, atoms]))
+                    .view([batch_size, heads, ACTION_SIZE as i64])
+            };
+            let target = self.target_net().forward_heads(&input_tensor, &legal_tensor, false, false);
+            // with a shared target network the learning network already gave `target`
+            let q = if double && !self.shared_target {
+                expected(&self.net_learn.forward_heads(&input_tensor, &legal_tensor, false, false))
+            } else {
+                expected(&target)
+            };
+            let actions = q
+                .masked_fill(&legal_tensor.eq(0.0).unsqueeze(1), f64::NEG_INFINITY)
+                .argmax(-1, true);
+            let chosen = target
+                .gather(2, &actions.unsqueeze(-1).expand(&[batch_size, heads, 1, atoms], false), false)
+                .view([batch_size * heads, atoms]);
+            (actions.view([-1]), returns.distribution(&chosen))
+        });
+        let actions = Vec::<i64>::from(actions.to_device(Device::Cpu));
+        let distributions: Vec<Vec<f32>> = distributions.to_device(Device::Cpu).into();
+        let mut per_head = actions.into_iter().zip(distributions);
+        Ok(any_legal
+            .into_iter()
+            .map(|any_legal| {
+                let row: Vec<(usize, Vec<f32>)> = per_head
+                    .by_ref()
+                    .take(heads as usize)
+                    .map(|(action, distribution)| (action as usize, distribution))
+                    .collect();
+                if any_legal {
+                    Some(row)
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+
+    fn heads(&self) -> usize {
+        self.spec.ensemble
+    }
+
+    fn forward_heads(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<Vec<Vec<Vec<f32>>>> {
+        let batch_size = batch.len() as i64;
+        let (heads, atoms) = (self.spec.ensemble as i64, self.spec.returns.atoms() as i64);
+        let input_tensor = self.input_tensor(batch);
+        let legal_tensor = self.legal_tensor(&legal.concat());
+        let raw = tch::no_grad(|| self.target_net().forward_heads(&input_tensor, &legal_tensor, false, false));
+        let q = self
+            .spec
+            .returns
+            .expected(&raw.view([batch_size * heads, ACTION_SIZE as i64, atoms]))
+            .view([batch_size, heads, ACTION_SIZE as i64]);
+        Ok((0..batch_size).map(|i| q.get(i).into()).collect())
+    }
 
-        let result = net.forward_t(&input_tensor.to(dev), false);
-        Ok(result.into())
+    fn forward_explore(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<Vec<Vec<f32>>> {
+        let input_tensor = self.input_tensor(batch);
+        let legal_tensor = self.legal_tensor(&legal.concat());
+        let result = self.target_net().forward_q(&input_tensor, &legal_tensor, false, true);
+        Ok(self.spec.returns.expected(&result).into())
+    }
+
+    fn forward_distribution(
+        &self,
+        batch: Vec<&[f32]>,
+        legal: Vec<&[i8]>,
+        actions: &[usize],
+    ) -> Result<Vec<Vec<f32>>> {
+        let (batch_size, atoms) = (actions.len() as i64, self.spec.returns.atoms() as i64);
+        let input_tensor = self.input_tensor(batch);
+        let legal_tensor = self.legal_tensor(&legal.concat());
+        let action_tensor = Tensor::of_slice(&actions.iter().map(|x| *x as i64).collect::<Vec<_>>())
+            .view([batch_size, 1, 1])
+            .expand(&[batch_size, 1, atoms], false)
+            .to(self.device);
+
+        let raw = tch::no_grad(|| self.target_net().forward_q(&input_tensor, &legal_tensor, false, false));
+        let chosen = raw.gather(1, &action_tensor, false).squeeze_dim(1);
+        Ok(self.spec.returns.distribution(&chosen).into())
+    }
+
+    fn returns(&self) -> Returns {
+        self.spec.returns
     }
 
     fn update_hard(&mut self) {
         self.vs_target.copy(&self.vs_learn).unwrap();
     }
 
-    fn update_soft(&mut self, _tau: f64) {
-        todo!()
+    fn update_soft(&mut self, tau: f64) {
+        let learn = self.vs_learn.variables();
+        let mut target = self.vs_target.variables();
+        tch::no_grad(|| {
+            for (name, var) in target.iter_mut() {
+                let src = &learn[name];
+                let mixed = src * tau + &*var * (1f64 - tau);
+                var.copy_(&mixed);
+            }
+        });
+    }
+
+    fn save(&self, name: &String, iteration: i64) -> Result<()> {
+        let meta = CheckpointMeta {
+            model: Model::QNet,
+            iteration,
+            network: self.spec.clone(),
+            optimizer: self.opt.to_json(),
+        };
+        meta.save(name)?;
+        self.vs_learn.save(checkpoint::file(name, checkpoint::LEARN))?;
+        self.target_vs().save(checkpoint::file(name, checkpoint::TARGET))?;
+        self.opt.save_state(&checkpoint::file(name, checkpoint::OPTIMIZER))?;
+        let cpu_net = checkpoint::file(name, checkpoint::CPU_NET);
+        self.export(iteration)?.save(&cpu_net.to_string_lossy())
+    }
+
+    fn load(&mut self, name: &String) -> Result<i64> {
+        if !checkpoint::file(name, checkpoint::META).exists() && Path::new(&legacy_file(name, "learn")).exists() {
+            return self.load_legacy(name);
+        }
+        let meta = CheckpointMeta::load(name)?;
+        ensure!(
+            meta.model == Model::QNet,
+            "{} holds a {:?} network, not a Q-network",
+            name,
+            meta.model
+        );
+        if meta.network != self.spec {
+            self.rebuild(meta.network.clone());
+        }
+        self.vs_learn.load(checkpoint::file(name, checkpoint::LEARN))?;
+        self.vs_target.load(checkpoint::file(name, checkpoint::TARGET))?;
+        self.opt
+            .load(&meta.optimizer, &checkpoint::file(name, checkpoint::OPTIMIZER))?;
+        Ok(meta.iteration)
+    }
+}
+
+fn copy_variables(vs: &VarStore, vars: &HashMap<String, Tensor>) -> Result<()> {
+    let mut variables = vs.variables();
+    tch::no_grad(|| {
+        for (name, var) in variables.iter_mut() {
+            let src = vars
+                .get(name)
+                .with_context(|| format!("no value for the variable {}", name))?;
+            ensure!(
+                var.size() == src.size(),
+                "the variable {} is {:?}, not {:?}",
+                name,
+                src.size(),
+                var.size()
+            );
+            var.copy_(src);
+        }
+        Ok(())
+    })
+}
+
+// the baseline saved its networks as `{name}_learn.vs` and `{name}_target.vs`
+fn legacy_file(name: &str, net: &str) -> String {
+    format!("{}_{}.vs", name, net)
+}
+
+// the variables of a baseline network, layer1 -> batch norm -> linear -> linear, under the
+// names of `NetworkSpec::default()`. only its first layer was named: the others were made at
+// the root, where the variable store suffixed every name it had already handed out, so the
+// unsuffixed root variables are the batch norm's and the linear ones are told apart by shape
+fn legacy_variables(path: &str) -> Result<HashMap<String, Tensor>> {
+    let actions = ACTION_SIZE as i64;
+    let mut vars = HashMap::new();
+    for (name, tensor) in Tensor::load_multi(path).with_context(|| format!("cannot read {}", path))? {
+        let renamed = match (name.as_str(), tensor.size().as_slice()) {
+            ("layer1.weight", _) => "hidden0.linear.weight",
+            ("layer1.bias", _) => "hidden0.linear.bias",
+            ("weight", _) => "hidden0.norm.weight",
+            ("bias", _) => "hidden0.norm.bias",
+            ("running_mean", _) => "hidden0.norm.running_mean",
+            ("running_var", _) => "hidden0.norm.running_var",
+            (_, [512, 512]) => "hidden1.linear.weight",
+            (_, [512]) => "hidden1.linear.bias",
+            (_, [a, 512]) if *a == actions => "out.weight",
+            (_, [a]) if *a == actions => "out.bias",
+            (_, size) => bail!("{} holds an unexpected variable {} of size {:?}", path, name, size),
+        };
+        vars.insert(renamed.to_string(), tensor);
+    }
+    // the baseline had no norm after its second layer; an identity one stands in, its running
+    // variance cancelling the epsilon batch norm adds to it
+    let eps = nn::BatchNormConfig::default().eps;
+    vars.insert("hidden1.norm.weight".to_string(), Tensor::ones(&[512], tch::kind::FLOAT_CPU));
+    vars.insert("hidden1.norm.bias".to_string(), Tensor::zeros(&[512], tch::kind::FLOAT_CPU));
+    vars.insert("hidden1.norm.running_mean".to_string(), Tensor::zeros(&[512], tch::kind::FLOAT_CPU));
+    vars.insert(
+        "hidden1.norm.running_var".to_string(),
+        Tensor::ones(&[512], tch::kind::FLOAT_CPU) * (1.0 - eps),
+    );
+    Ok(vars)
+}
+
+#[test]
+fn test_update_soft() {
+    let spec = NetworkSpec {
+        widths: vec![16],
+        ..NetworkSpec::default()
+    };
+    let mut qnet = QNet::with_spec(spec);
+    let learn: HashMap<String, Tensor> = tch::no_grad(|| {
+        qnet.vs_learn
+            .variables()
+            .into_iter()
+            .map(|(name, var)| (name, var.copy()))
+            .collect()
+    });
+    let target = qnet.target_variables();
+
+    qnet.update_soft(0.25);
+    let mixed = qnet.target_variables();
+    assert_eq!(mixed.len(), learn.len());
+    for (name, var) in mixed.iter() {
+        let expected = &learn[name] * 0.25 + &target[name] * 0.75;
+        assert!(var.allclose(&expected, 1e-5, 1e-6, false), "{} was not mixed", name);
     }
 
-    fn save(&self, name: &String) {
-        self.vs_learn.save(name.clone() + "_learn.vs");
-        self.vs_target.save(name.clone() + "_target.vs");
+    qnet.update_soft(1.0);
+    for (name, var) in qnet.target_variables().iter() {
+        assert!(var.allclose(&learn[name], 1e-5, 1e-6, false), "{} was not copied", name);
     }
+}
+
+#[test]
+fn test_shared_target() {
+    use super::network::Norm;
 
-    fn load(&mut self, name: &String) {
-        self.vs_learn.load(name.clone() + "_learn.vs");
-        self.vs_target.load(name.clone() + "_target.vs");
+    let spec = NetworkSpec {
+        widths: vec![16],
+        norm: Norm::None,
+        ..NetworkSpec::default()
+    };
+    let mut qnet = QNet::with_spec(spec);
+    qnet.share_target(true);
+    let state: Vec<f32> = (0..STATE_SIZE).map(|i| (i % 3 == 0) as i32 as f32).collect();
+    let legal = vec![1i8; ACTION_SIZE];
+    let before = qnet.forward(vec![&state[..]], vec![&legal[..]]).unwrap().pop().unwrap();
+
+    // the step shows in `forward` without any target update
+    qnet.train(&state, &legal, &[7], &[10.0], &[1.0], &[1.0]).unwrap();
+    let after = qnet.forward(vec![&state[..]], vec![&legal[..]]).unwrap().pop().unwrap();
+    assert!(after[7] > before[7]);
+    let learn = qnet.vs_learn.variables();
+    for (name, var) in qnet.target_variables().iter() {
+        assert!(var.allclose(&learn[name], 0.0, 0.0, false), "{} is not the learning network's", name);
     }
 }
+
+#[test]
+fn test_load_legacy_checkpoint() {
+    use crate::learn::temp_file::TempFile;
+    use tch::nn::ModuleT;
+
+    // the network the baseline saved, built the way it built it
+    let vs = nn::VarStore::new(Device::Cpu);
+    let root = vs.root();
+    let net = nn::seq_t()
+        .add(nn::linear(&root / "layer1", STATE_SIZE as i64, 512, Default::default()))
+        .add(nn::batch_norm1d(&root, 512, Default::default()))
+        .add_fn(|xs| xs.relu())
+        .add(nn::linear(&root, 512, 512, Default::default()))
+        .add_fn(|xs| xs.relu())
+        .add(nn::linear(&root, 512, ACTION_SIZE as i64, Default::default()));
+    // moves the running statistics off their initial values
+    let xs = Tensor::rand(&[8, STATE_SIZE as i64], tch::kind::FLOAT_CPU);
+    let _ = net.forward_t(&xs, true);
+
+    let name = TempFile::new("legacy");
+    let (learn, target) = (name.with_suffix("_learn.vs"), name.with_suffix("_target.vs"));
+    vs.save(learn.path()).unwrap();
+    vs.save(target.path()).unwrap();
+
+    let mut qnet = QNet::new();
+    assert_eq!(qnet.load(&name.path().to_string()).unwrap(), 0);
+    assert_eq!(*qnet.spec(), NetworkSpec::default());
+
+    let state: Vec<f32> = (0..STATE_SIZE).map(|i| (i % 7 == 0) as i32 as f32).collect();
+    let legal = vec![1i8; ACTION_SIZE];
+    let expected: Vec<f32> = tch::no_grad(|| net.forward_t(&Tensor::of_slice(&state).view([1, -1]), false))
+        .view([-1])
+        .into();
+    let actual = qnet.forward(vec![&state[..]], vec![&legal[..]]).unwrap().pop().unwrap();
+    for (x, y) in expected.iter().zip(actual.iter()) {
+        assert!((x - y).abs() < 1e-4 * x.abs().max(1.0), "{} != {}", x, y);
+    }
+}
+
+#[test]
+fn test_greedy_distributions_per_head() {
+    use super::network::Norm;
+
+    let spec = NetworkSpec {
+        widths: vec![16],
+        norm: Norm::None,
+        returns: Returns::Categorical {
+            atoms: 5,
+            v_min: -2.0,
+            v_max: 2.0,
+        },
+        ensemble: 3,
+        ..NetworkSpec::default()
+    };
+    let qnet = QNet::with_spec(spec);
+    let state: Vec<f32> = (0..STATE_SIZE).map(|i| (i % 5 == 0) as i32 as f32).collect();
+    let legal: Vec<i8> = (0..ACTION_SIZE).map(|i| (i % 4 == 0) as i8).collect();
+    let none = vec![0i8; ACTION_SIZE];
+
+    let next = qnet
+        .greedy_distributions(vec![&state[..], &state[..]], vec![&legal[..], &none[..]], false)
+        .unwrap();
+    assert!(next[1].is_none());
+    let heads = next[0].as_ref().unwrap();
+    assert_eq!(heads.len(), 3);
+
+    let q = qnet.forward_heads(vec![&state[..]], vec![&legal[..]]).unwrap().pop().unwrap();
+    for ((action, distribution), q) in heads.iter().zip(q) {
+        assert_eq!(legal[*action], 1);
+        let best = (0..ACTION_SIZE).filter(|a| legal[*a] == 1).all(|a| q[a] <= q[*action]);
+        assert!(best, "{} is not the greedy action of its head", action);
+        assert_eq!(distribution.len(), 5);
+        assert!((distribution.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn test_td_errors_of_active_heads() {
+    use super::network::Norm;
+
+    let spec = NetworkSpec {
+        widths: vec![16],
+        norm: Norm::None,
+        ensemble: 2,
+        ..NetworkSpec::default()
+    };
+    let mut qnet = QNet::with_spec(spec);
+    qnet.update_hard();
+    let state: Vec<f32> = (0..STATE_SIZE).map(|i| (i % 3 == 0) as i32 as f32).collect();
+    let legal = vec![1i8; ACTION_SIZE];
+    let action = 7;
+    let q = qnet.forward_heads(vec![&state[..]], vec![&legal[..]]).unwrap().pop().unwrap();
+    let (q0, q1) = (q[0][action], q[1][action]);
+
+    // the first row only trains the second head, which none trains on the second row
+    let states = [&state[..], &state[..]].concat();
+    let legal = [&legal[..], &legal[..]].concat();
+    let targets = [1.0, -1.0, 1.0, -1.0];
+    let masks = [0.0, 1.0, 0.0, 0.0];
+    let (td_errors, _) = qnet
+        .train(&states, &legal, &[action, action], &targets, &[1.0, 1.0], &masks)
+        .unwrap();
+    assert!((td_errors[0] - (-1.0 - q1)).abs() < 1e-4);
+    assert!((td_errors[1] - ((1.0 - q0) + (-1.0 - q1)) / 2.0).abs() < 1e-4);
+}
@@ -0,0 +1,193 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use cetkaik_full_state_transition::{Config, Consequence};
+use chrono::Utc;
+use serde_json::{json, Value};
+
+use super::network::NetworkSpec;
+use crate::learn::state_to_feature::{ACTION_SIZE, FEATURE_VERSION, STATE_SIZE};
+
+const FORMAT: &str = "cerke-dqn-checkpoint";
+const FORMAT_VERSION: u64 = 1;
+
+// a checkpoint is a directory holding `meta.json` and the tensors of the two networks and
+// of the optimizer, with the target network of a `QNet` exported for `CpuNet::load` too
+pub const META: &str = "meta.json";
+pub const LEARN: &str = "learn.ot";
+pub const TARGET: &str = "target.ot";
+pub const OPTIMIZER: &str = "optimizer.ot";
+pub const CPU_NET: &str = "cpu_net.bin";
+
+pub fn file(dir: &str, name: &str) -> PathBuf {
+    Path::new(dir).join(name)
+}
+
+fn consequence_to_json(consequence: &Consequence) -> Value {
+    match consequence {
+        Consequence::Allowed => json!("allowed"),
+        Consequence::Forbidden => json!("forbidden"),
+        Consequence::Penalized { penalty, is_a_hand } => json!({
+            "penalized": { "penalty": penalty, "is_a_hand": is_a_hand },
+        }),
+    }
+}
+
+// the rules the agent was trained under, field by field
+pub(crate) fn rule_config() -> Value {
+    let config = Config::cerke_online_alpha();
+    json!({
+        "step_tam_is_a_hand": config.step_tam_is_a_hand,
+        "tam_itself_is_tam_hue": config.tam_itself_is_tam_hue,
+        "moving_tam_immediately_after_tam_has_moved":
+            consequence_to_json(&config.moving_tam_immediately_after_tam_has_moved),
+        "tam_mun_mok": consequence_to_json(&config.tam_mun_mok),
+        "failure_to_complete_the_move_means_exempt_from_kut2_tam2":
+            config.failure_to_complete_the_move_means_exempt_from_kut2_tam2,
+    })
+}
+
+// what a checkpoint holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    // learning and target networks of a `QNet`
+    QNet,
+    // the single network of a `PolicyValueNet`
+    PolicyValue,
+}
+
+const MODELS: [(&str, Model); 2] = [("q", Model::QNet), ("policy_value", Model::PolicyValue)];
+
+impl Model {
+    fn name(self) -> &'static str {
+        MODELS.iter().find(|(_name, x)| *x == self).unwrap().0
+    }
+}
+
+pub struct CheckpointMeta {
+    pub model: Model,
+    pub iteration: i64,
+    pub network: NetworkSpec,
+    // what `Optimizer::to_json` wrote
+    pub optimizer: Value,
+}
+
+impl CheckpointMeta {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "format": FORMAT,
+            "version": FORMAT_VERSION,
+            "created": Utc::now().to_rfc3339(),
+            "state_size": STATE_SIZE,
+            "action_size": ACTION_SIZE,
+            "feature_version": FEATURE_VERSION,
+            "rule_config": rule_config(),
+            "model": self.model.name(),
+            "iteration": self.iteration,
+            "network": self.network.to_json(),
+            "optimizer": self.optimizer,
+        })
+    }
+
+    // fails when the checkpoint does not fit the features, actions or rules of this build
+    pub fn from_json(value: &Value) -> Result<Self> {
+        if value["format"].as_str() != Some(FORMAT) {
+            bail!("not a checkpoint");
+        }
+        let version = value["version"].as_u64().context("checkpoint lacks its version")?;
+        ensure!(
+            version == FORMAT_VERSION,
+            "checkpoint format version {} is not supported (expected {})",
+            version,
+            FORMAT_VERSION
+        );
+
+        let size = |field: &str| {
+            value[field]
+                .as_u64()
+                .with_context(|| format!("checkpoint lacks `{}`", field))
+        };
+        let (state_size, action_size) = (size("state_size")? as usize, size("action_size")? as usize);
+        let feature_version = size("feature_version")?;
+        ensure!(
+            state_size == STATE_SIZE
+                && action_size == ACTION_SIZE
+                && feature_version == FEATURE_VERSION as u64,
+            "the feature encoding has changed: checkpoint was written with STATE_SIZE={} ACTION_SIZE={} \
+             FEATURE_VERSION={} but this build uses STATE_SIZE={} ACTION_SIZE={} FEATURE_VERSION={}",
+            state_size,
+            action_size,
+            feature_version,
+            STATE_SIZE,
+            ACTION_SIZE,
+            FEATURE_VERSION
+        );
+
+        let rules = &value["rule_config"];
+        ensure!(!rules.is_null(), "checkpoint lacks `rule_config`");
+        ensure!(
+            *rules == rule_config(),
+            "checkpoint was trained under the rules {} but this build plays {}",
+            rules,
+            rule_config()
+        );
+
+        let model = value.get("model").context("checkpoint lacks `model`")?;
+        let model = match MODELS.iter().find(|(name, _)| Some(*name) == model.as_str()) {
+            Some((_, model)) => *model,
+            None => bail!("checkpoint holds an unknown model {}", model),
+        };
+
+        Ok(Self {
+            model,
+            iteration: value["iteration"]
+                .as_i64()
+                .context("checkpoint lacks `iteration`")?,
+            network: NetworkSpec::from_json(&value["network"])?,
+            optimizer: value["optimizer"].clone(),
+        })
+    }
+
+    pub fn save(&self, dir: &str) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(file(dir, META), serde_json::to_string_pretty(&self.to_json())?)?;
+        Ok(())
+    }
+
+    pub fn load(dir: &str) -> Result<Self> {
+        let path = file(dir, META);
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("{} is not a checkpoint: cannot read {}", dir, path.display()))?;
+        Self::from_json(&serde_json::from_str(&text)?)
+            .with_context(|| format!("cannot load checkpoint {}", dir))
+    }
+}
+
+#[test]
+fn test_checkpoint_meta() {
+    let meta = CheckpointMeta {
+        model: Model::PolicyValue,
+        iteration: 42,
+        network: NetworkSpec::default(),
+        optimizer: json!({ "lr": 0.001 }),
+    };
+    let loaded = CheckpointMeta::from_json(&meta.to_json()).unwrap();
+    assert_eq!(loaded.model, Model::PolicyValue);
+    assert_eq!(loaded.iteration, 42);
+    assert_eq!(loaded.network, meta.network);
+    assert_eq!(loaded.optimizer, meta.optimizer);
+
+    let mut value = meta.to_json();
+    value["state_size"] = json!(STATE_SIZE + 1);
+    let error = CheckpointMeta::from_json(&value).err().unwrap().to_string();
+    assert!(error.contains("feature encoding has changed"));
+
+    let mut value = meta.to_json();
+    value["rule_config"] = json!("Config { other: true }");
+    assert!(CheckpointMeta::from_json(&value).is_err());
+
+    let mut value = meta.to_json();
+    value["rule_config"]["tam_itself_is_tam_hue"] = json!(!Config::cerke_online_alpha().tam_itself_is_tam_hue);
+    let error = CheckpointMeta::from_json(&value).err().unwrap().to_string();
+    assert!(error.contains("trained under the rules"));
+}
@@ -3,9 +3,14 @@ use cetkaik_full_state_transition::{Config, IfTaxot, Scores, Victor, message::{A
 
 use rand_distr::StandardNormal;
 
-use crate::learn::memory::Experience;
+use crate::learn::{
+    episode::{Episode, Step},
+    memory::{NStep, SharedMemory},
+};
 
-use super::agent::CerkeAgent;
+#[cfg(feature = "torch")]
+use super::{agent::CerkeAgent, service::InferenceService};
+use super::{policy::Policy, replay::EncodedPhase};
 
 pub enum ActionResult {
     Finish(f32),
@@ -216,63 +221,178 @@ impl ParallelCerke{
         }
     }
 
+    // reward seen by the player who moved in `last` once the game reached `next`
+    fn side_reward(next: &Phase, last: &Phase) -> f32 {
+        let v = Self::score_delta(next, last);
+        match last.whose_turn() {
+            cetkaik_core::absolute::Side::ASide => v,
+            cetkaik_core::absolute::Side::IASide => -v,
+        }
+    }
+
+    #[cfg(feature = "torch")]
     pub fn iteration(&mut self, agent: &mut CerkeAgent) {
-        let mut last_state: (Vec<Option<Phase>>, Vec<Option<Phase>>) = (Vec::new(),Vec::new());
+        let memory = agent.memory();
+        let episodes = match agent.service() {
+            Some(service) => {
+                let config = agent.config();
+                let heads = agent.thompson_heads(self.envs.len());
+                self.rollout_with_service(service, config.actors, heads, config.n_step, config.gamma, &memory)
+            }
+            None => self.rollout(agent, &memory),
+        };
+        agent.put_episodes(episodes);
+        agent.train();
+        agent.finish_iteration();
+    }
+
+    // plays the games with `agent` choosing moves and puts the experiences into `memory`;
+    // returns the games that finished
+    #[cfg(feature = "torch")]
+    pub fn rollout(&mut self, agent: &CerkeAgent, memory: &SharedMemory<EncodedPhase, usize>) -> Vec<Episode<Phase, usize>> {
+        let (n, gamma) = (agent.config().n_step, agent.config().gamma);
+        // each game is played by one head of an ensemble
+        let heads = agent.thompson_heads(self.envs.len());
+        self.play(agent, heads.as_deref(), n, gamma, Some(memory))
+    }
+
+    // `rollout` split over `actors` threads, each acting through its own handle of `service`
+    // so that their forwards are batched together
+    #[cfg(feature = "torch")]
+    pub fn rollout_with_service(
+        &mut self,
+        service: &InferenceService,
+        actors: usize,
+        heads: Option<Vec<usize>>,
+        n: usize,
+        gamma: f32,
+        memory: &SharedMemory<EncodedPhase, usize>,
+    ) -> Vec<Episode<Phase, usize>> {
+        let size = ((self.envs.len() + actors.max(1) - 1) / actors.max(1)).max(1);
+        let mut envs = std::mem::take(&mut self.envs).into_iter();
+        let mut heads = heads.map(|heads| heads.into_iter());
+        let mut threads = Vec::new();
+        loop {
+            let chunk: Vec<CerkeEnv> = envs.by_ref().take(size).collect();
+            if chunk.is_empty() {
+                break;
+            }
+            let chunk_heads: Option<Vec<usize>> = heads.as_mut().map(|heads| heads.by_ref().take(chunk.len()).collect());
+            let (service, memory) = (service.clone(), memory.clone());
+            threads.push(std::thread::spawn(move || {
+                let mut actor = ParallelCerke { envs: chunk };
+                let episodes = actor.play(&service, chunk_heads.as_deref(), n, gamma, Some(&memory));
+                (actor.envs, episodes)
+            }));
+        }
+        let mut episodes = Vec::new();
+        for thread in threads {
+            let (envs, finished) = thread.join().expect("an actor thread panicked");
+            self.envs.extend(envs);
+            episodes.extend(finished);
+        }
+        episodes
+    }
+
+    // plays the games with `policy` and only returns the games that finished
+    pub fn self_play<P: Policy>(&mut self, policy: &P) -> Vec<Episode<Phase, usize>> {
+        self.play(policy, None, 1, 1.0, None)
+    }
+
+    fn play<P: Policy>(
+        &mut self,
+        agent: &P,
+        heads: Option<&[usize]>,
+        n: usize,
+        gamma: f32,
+        memory: Option<&SharedMemory<EncodedPhase, usize>>,
+    ) -> Vec<Episode<Phase, usize>> {
+        let mut pending: (Vec<NStep<Phase, usize>>, Vec<NStep<Phase, usize>>) = (Vec::new(),Vec::new());
         let mut finished = Vec::new();
+        let mut episodes: Vec<Episode<Phase, usize>> = Vec::new();
         for _i in 0..self.envs.len() {
-            last_state.0.push(None);
-            last_state.1.push(None);
+            pending.0.push(NStep::new(n, gamma));
+            pending.1.push(NStep::new(n, gamma));
             finished.push(false);
+            episodes.push(Episode::new());
         }
+        let mut completed = Vec::new();
 
         for _turn in 0..40 {
             let states: Vec<Phase> = self.envs.iter().map(|environment| environment.observe()).collect();
 
-            let mut actions: Vec<Option<(Action, usize)>> = agent.parallel_select_action(&states).into_iter().map(Some).collect();
+            let mut actions: Vec<Option<(Action, usize)>> = agent.parallel_select_action_with_heads(&states, heads).into_iter().map(Some).collect();
 
             let mut states: Vec<Option<Phase>> = states.into_iter().map(Some).collect();
+            let mut experiences = Vec::new();
 
             for index in 0..self.envs.len() {
                 if finished[index] {
                     continue;
                 }
-                let mut environment = self.envs.get_mut(index).unwrap();
+                let environment = self.envs.get_mut(index).unwrap();
                 let prev_env = states.get_mut(index).unwrap().take().unwrap();
                 let (act, atc_id) = actions.get_mut(index).unwrap().take().unwrap();
 
-                let last_state = match prev_env.whose_turn() {
-                    cetkaik_core::absolute::Side::ASide => &mut last_state.0,
-                    cetkaik_core::absolute::Side::IASide => &mut last_state.1,
-                }.get_mut(index).unwrap();
-                
+                let side = match prev_env.whose_turn() {
+                    cetkaik_core::absolute::Side::ASide => 0,
+                    cetkaik_core::absolute::Side::IASide => 1,
+                };
+                let (own, other) = match prev_env.whose_turn() {
+                    cetkaik_core::absolute::Side::ASide => (&mut pending.0[index], &mut pending.1[index]),
+                    cetkaik_core::absolute::Side::IASide => (&mut pending.1[index], &mut pending.0[index]),
+                };
+
                 let res = environment.act(act);
 
-                if let Some(last_state) = last_state {
-                    let v = Self::score_delta(&prev_env,&last_state);
-                    let v = match last_state.whose_turn() {
-                        cetkaik_core::absolute::Side::ASide => v,
-                        cetkaik_core::absolute::Side::IASide => -v,
-                    };
-                    agent.put_memory(Experience {
-                        current_state: last_state.clone(),
-                        next_state: prev_env.clone(),
-                        action: atc_id,
-                        value: v,
-                    });
+                let reward = own.latest().map(|last_state| Self::side_reward(&prev_env, last_state));
+                if let Some(reward) = reward {
+                    if let Some(experience) = own.step(reward, &prev_env) {
+                        experiences.push(experience.map_state(EncodedPhase::encode));
+                    }
+                    episodes[index].sides[side].last_mut().unwrap().reward = reward;
                 }
-                *last_state = Some(prev_env);
 
-                finished[index] = match res {
+                match res {
                     ActionResult::Finish(v) => {
-                        true
+                        // the game ends on this move: close the transitions of both sides
+                        let reward = other.latest().map(|last_state| Self::side_reward(&prev_env, last_state) - v);
+                        if let Some(reward) = reward {
+                            for experience in other.finish(reward) {
+                                experiences.push(experience.map_state(EncodedPhase::encode));
+                            }
+                            episodes[index].sides[1 - side].last_mut().unwrap().reward = reward;
+                        }
+                        episodes[index].sides[side].push(Step {
+                            state: prev_env.clone(),
+                            action: atc_id,
+                            reward: v,
+                        });
+                        own.push(prev_env, atc_id);
+                        for experience in own.finish(v) {
+                            experiences.push(experience.map_state(EncodedPhase::encode));
+                        }
+
+                        let mut episode = std::mem::take(&mut episodes[index]);
+                        episode.finish = v;
+                        episode.finishing_side = side;
+                        completed.push(episode);
+                        finished[index] = true;
                     },
                     ActionResult::Continue => {
-                        false
+                        episodes[index].sides[side].push(Step {
+                            state: prev_env.clone(),
+                            action: atc_id,
+                            reward: 0f32,
+                        });
+                        own.push(prev_env, atc_id);
                     },
                 };
             }
-
+            if let Some(memory) = memory {
+                memory.put_all(experiences);
+            }
         }
-        agent.train();
+        completed
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,774 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use super::{
+    learner::Brain,
+    metrics::TrainMetrics,
+    network::{head_var, Activation, Encoder, Head, NetworkSpec, Norm, Returns, ATOM_RANK},
+    policy::Policy,
+};
+use crate::learn::state_to_feature::{ACTION_SIZE, FEATURE_VERSION, STATE_SIZE};
+
+const MAGIC: &[u8; 8] = b"CRKINFER";
+const FORMAT_VERSION: u32 = 1;
+// same as the default of tch's batch and layer norms
+const NORM_EPS: f32 = 1e-5;
+const PLANES: usize = 42;
+const SQUARES: usize = 81;
+
+// dense layer with any batch norm that followed it folded in
+struct Dense {
+    // row-major [out, fan_in]
+    weight: Vec<f32>,
+    bias: Vec<f32>,
+    fan_in: usize,
+}
+
+impl Dense {
+    fn forward(&self, xs: &[f32]) -> Vec<f32> {
+        self.weight
+            .chunks(self.fan_in)
+            .zip(self.bias.iter())
+            .map(|(row, b)| b + row.iter().zip(xs).map(|(w, x)| w * x).sum::<f32>())
+            .collect()
+    }
+
+    // 3x3 convolution with zero padding of `[in, 81]` planes into `[out, 81]` planes,
+    // the weight being [out, in, 3, 3]
+    fn conv3x3(&self, xs: &[f32]) -> Vec<f32> {
+        let in_channels = self.fan_in / 9;
+        let mut ys = vec![0f32; self.bias.len() * SQUARES];
+        for (o, b) in self.bias.iter().enumerate() {
+            for square in 0..SQUARES {
+                let (row, column) = (square / 9, square % 9);
+                let mut acc = *b;
+                for i in 0..in_channels {
+                    let kernel = &self.weight[(o * in_channels + i) * 9..(o * in_channels + i + 1) * 9];
+                    for (k, w) in kernel.iter().enumerate() {
+                        let (r, c) = (row + k / 3, column + k % 3);
+                        if (1..=9).contains(&r) && (1..=9).contains(&c) {
+                            acc += w * xs[i * SQUARES + (r - 1) * 9 + (c - 1)];
+                        }
+                    }
+                }
+                ys[o * SQUARES + square] = acc;
+            }
+        }
+        ys
+    }
+
+    fn mean(mut members: Vec<Dense>) -> Dense {
+        let mut mean = members.pop().unwrap();
+        let n = (members.len() + 1) as f32;
+        for member in members {
+            for (x, y) in mean.weight.iter_mut().zip(member.weight) {
+                *x += y;
+            }
+            for (x, y) in mean.bias.iter_mut().zip(member.bias) {
+                *x += y;
+            }
+        }
+        for x in mean.weight.iter_mut().chain(mean.bias.iter_mut()) {
+            *x /= n;
+        }
+        mean
+    }
+
+    fn fold_batch_norm(&mut self, norm: &BatchNormStats) {
+        for (o, (row, b)) in self.weight.chunks_mut(self.fan_in).zip(self.bias.iter_mut()).enumerate() {
+            let scale = norm.weight[o] / (norm.running_var[o] + NORM_EPS).sqrt();
+            for w in row.iter_mut() {
+                *w *= scale;
+            }
+            *b = (*b - norm.running_mean[o]) * scale + norm.bias[o];
+        }
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(&(self.fan_in as u32).to_le_bytes())?;
+        w.write_all(&(self.bias.len() as u32).to_le_bytes())?;
+        write_floats(w, &self.weight)?;
+        write_floats(w, &self.bias)
+    }
+
+    fn read_from<R: Read>(r: &mut R, fan_in: usize, out: usize) -> Result<Self> {
+        let (file_fan_in, file_out) = (read_u32(r)? as usize, read_u32(r)? as usize);
+        ensure!(
+            (file_fan_in, file_out) == (fan_in, out),
+            "exported layer is {}x{} but the spec needs {}x{}",
+            file_out,
+            file_fan_in,
+            out,
+            fan_in
+        );
+        Ok(Self {
+            weight: read_floats(r, fan_in * out)?,
+            bias: read_floats(r, out)?,
+            fan_in,
+        })
+    }
+}
+
+// see `network::Output`
+enum Output {
+    Dense(Dense),
+    Factorised {
+        code: Dense,
+        // row-major [action, rank]
+        embedding: Vec<f32>,
+        // row-major [action, atom]
+        bias: Vec<f32>,
+    },
+}
+
+impl Output {
+    // row-major [action, atom]
+    fn forward(&self, xs: &[f32]) -> Vec<f32> {
+        match self {
+            Output::Dense(dense) => dense.forward(xs),
+            Output::Factorised { code, embedding, bias } => {
+                let atoms = bias.len() / ACTION_SIZE;
+                // row-major [rank, atom]
+                let code = code.forward(xs);
+                let mut ys = bias.clone();
+                for (y, e) in ys.chunks_mut(atoms).zip(embedding.chunks(ATOM_RANK as usize)) {
+                    for (w, c) in e.iter().zip(code.chunks(atoms)) {
+                        for (y, c) in y.iter_mut().zip(c) {
+                            *y += w * c;
+                        }
+                    }
+                }
+                ys
+            }
+        }
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        match self {
+            Output::Dense(dense) => dense.write_to(w),
+            Output::Factorised { code, embedding, bias } => {
+                code.write_to(w)?;
+                write_floats(w, embedding)?;
+                write_floats(w, bias)
+            }
+        }
+    }
+
+    fn read_from<R: Read>(r: &mut R, fan_in: usize, atoms: usize) -> Result<Self> {
+        if atoms == 1 {
+            return Ok(Output::Dense(Dense::read_from(r, fan_in, ACTION_SIZE)?));
+        }
+        let rank = ATOM_RANK as usize;
+        Ok(Output::Factorised {
+            code: Dense::read_from(r, fan_in, rank * atoms)?,
+            embedding: read_floats(r, ACTION_SIZE * rank)?,
+            bias: read_floats(r, ACTION_SIZE * atoms)?,
+        })
+    }
+}
+
+struct BatchNormStats {
+    weight: Vec<f32>,
+    bias: Vec<f32>,
+    running_mean: Vec<f32>,
+    running_var: Vec<f32>,
+}
+
+struct LayerNorm {
+    weight: Vec<f32>,
+    bias: Vec<f32>,
+}
+
+impl LayerNorm {
+    fn forward(&self, xs: &mut [f32]) {
+        let n = xs.len() as f32;
+        let mean = xs.iter().sum::<f32>() / n;
+        let var = xs.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / n;
+        let inv = 1.0 / (var + NORM_EPS).sqrt();
+        for (i, x) in xs.iter_mut().enumerate() {
+            *x = (*x - mean) * inv * self.weight[i] + self.bias[i];
+        }
+    }
+}
+
+// Abramowitz and Stegun 7.1.26, accurate to 1.5e-7
+fn erf(x: f32) -> f32 {
+    let t = 1.0 / (1.0 + 0.327_591_1 * x.abs());
+    let y = 1.0
+        - (((((1.061_405_4 * t - 1.453_152_1) * t) + 1.421_413_7) * t - 0.284_496_72) * t
+            + 0.254_829_6)
+            * t
+            * (-x * x).exp();
+    y.copysign(x)
+}
+
+fn activate(activation: Activation, xs: &mut [f32]) {
+    for x in xs.iter_mut() {
+        *x = match activation {
+            Activation::Relu => x.max(0.0),
+            Activation::LeakyRelu => {
+                if *x < 0.0 {
+                    0.01 * *x
+                } else {
+                    *x
+                }
+            }
+            Activation::Tanh => x.tanh(),
+            Activation::Gelu => 0.5 * *x * (1.0 + erf(*x / std::f32::consts::SQRT_2)),
+        };
+    }
+}
+
+fn relu(xs: &mut [f32]) {
+    activate(Activation::Relu, xs);
+}
+
+struct ResidualBlock {
+    conv1: Dense,
+    conv2: Dense,
+}
+
+struct Hidden {
+    dense: Dense,
+    layer_norm: Option<LayerNorm>,
+}
+
+// a `QNet` network evaluated in plain Rust on the CPU, with noise and dropout off
+// as in evaluation mode
+pub struct CpuNet {
+    spec: NetworkSpec,
+    stem: Option<Dense>,
+    blocks: Vec<ResidualBlock>,
+    hidden: Vec<Hidden>,
+    // `out` for a plain head, `value` and `advantage` for a dueling one
+    heads: Vec<Output>,
+    iteration: i64,
+}
+
+fn values(vars: &HashMap<String, Vec<f32>>, name: &str) -> Result<Vec<f32>> {
+    let var = vars
+        .get(name)
+        .with_context(|| format!("exported network lacks {}", name))?;
+    Ok(var.clone())
+}
+
+fn dense_from_vars(vars: &HashMap<String, Vec<f32>>, prefix: &str, fan_in: usize, noisy: bool) -> Result<Dense> {
+    let (weight, bias) = if noisy { ("weight_mu", "bias_mu") } else { ("weight", "bias") };
+    let weight = values(vars, &format!("{}.{}", prefix, weight))?;
+    let bias = match vars.get(&format!("{}.{}", prefix, bias)) {
+        Some(_) => values(vars, &format!("{}.{}", prefix, bias))?,
+        // convolutions have no bias
+        None => vec![0f32; weight.len() / fan_in],
+    };
+    ensure!(
+        weight.len() == bias.len() * fan_in,
+        "exported {} does not take {} inputs",
+        prefix,
+        fan_in
+    );
+    Ok(Dense {
+        weight,
+        bias,
+        fan_in,
+    })
+}
+
+fn batch_norm_from_vars(vars: &HashMap<String, Vec<f32>>, prefix: &str) -> Result<BatchNormStats> {
+    Ok(BatchNormStats {
+        weight: values(vars, &format!("{}.weight", prefix))?,
+        bias: values(vars, &format!("{}.bias", prefix))?,
+        running_mean: values(vars, &format!("{}.running_mean", prefix))?,
+        running_var: values(vars, &format!("{}.running_var", prefix))?,
+    })
+}
+
+impl CpuNet {
+    // `vars` are the flattened variables of a `VarStore` built by `layers::q_module` for `spec`,
+    // by name; `QNet::export` gives them
+    pub fn from_vars(spec: NetworkSpec, vars: &HashMap<String, Vec<f32>>, iteration: i64) -> Result<Self> {
+        let noisy = spec.noisy.is_some();
+
+        let (mut stem, mut blocks) = (None, Vec::new());
+        if let Encoder::Residual { channels, blocks: count } = spec.encoder {
+            let channels = channels as usize;
+            let mut conv = dense_from_vars(vars, "encoder.stem", PLANES * 9, false)?;
+            conv.fold_batch_norm(&batch_norm_from_vars(vars, "encoder.stem_bn")?);
+            stem = Some(conv);
+            for i in 0..count {
+                let prefix = format!("encoder.block{}", i);
+                let mut conv1 = dense_from_vars(vars, &format!("{}.conv1", prefix), channels * 9, false)?;
+                conv1.fold_batch_norm(&batch_norm_from_vars(vars, &format!("{}.bn1", prefix))?);
+                let mut conv2 = dense_from_vars(vars, &format!("{}.conv2", prefix), channels * 9, false)?;
+                conv2.fold_batch_norm(&batch_norm_from_vars(vars, &format!("{}.bn2", prefix))?);
+                blocks.push(ResidualBlock { conv1, conv2 });
+            }
+        }
+
+        let mut hidden = Vec::with_capacity(spec.depth());
+        let mut width = spec.encoder.width() as usize;
+        for (i, out) in spec.widths.iter().enumerate() {
+            let prefix = format!("hidden{}", i);
+            let mut dense = dense_from_vars(vars, &format!("{}.linear", prefix), width, noisy)?;
+            let mut layer_norm = None;
+            match spec.norm {
+                Norm::None => {}
+                Norm::Batch => dense.fold_batch_norm(&batch_norm_from_vars(vars, &format!("{}.norm", prefix))?),
+                Norm::Layer => {
+                    layer_norm = Some(LayerNorm {
+                        weight: values(vars, &format!("{}.norm.weight", prefix))?,
+                        bias: values(vars, &format!("{}.norm.bias", prefix))?,
+                    })
+                }
+            }
+            hidden.push(Hidden { dense, layer_norm });
+            width = *out as usize;
+        }
+
+        // the heads of an ensemble are linear in their weights, so their mean is a single
+        // head with the mean weights
+        let head = |name: &str| -> Result<Dense> {
+            let members = (0..spec.ensemble)
+                .map(|h| dense_from_vars(vars, &head_var(spec.ensemble, h, name), width, noisy))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Dense::mean(members))
+        };
+        // ensembles have expected returns only, so a factorised output has a single member
+        let output = |name: &str| -> Result<Output> {
+            if spec.returns.atoms() == 1 {
+                return Ok(Output::Dense(head(name)?));
+            }
+            Ok(Output::Factorised {
+                code: dense_from_vars(vars, &format!("{}.code", name), width, noisy)?,
+                embedding: values(vars, &format!("{}.embedding", name))?,
+                bias: values(vars, &format!("{}.bias", name))?,
+            })
+        };
+        let heads = match spec.head {
+            Head::Plain => vec![output("out")?],
+            Head::Dueling => vec![Output::Dense(head("value")?), output("advantage")?],
+        };
+
+        Ok(Self {
+            spec,
+            stem,
+            blocks,
+            hidden,
+            heads,
+            iteration,
+        })
+    }
+
+    pub fn spec(&self) -> &NetworkSpec {
+        &self.spec
+    }
+
+    // expected values of every action
+    pub fn q_values(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<f32>> {
+        batch
+            .iter()
+            .zip(legal.iter())
+            .map(|(xs, legal)| self.expected(&self.forward_raw(xs, legal)))
+            .collect()
+    }
+
+    // [action, atom] raw outputs for one feature vector
+    fn forward_raw(&self, xs: &[f32], legal: &[i8]) -> Vec<f32> {
+        let mut ys = match &self.stem {
+            None => xs.to_vec(),
+            Some(stem) => {
+                let mut planes = vec![0f32; PLANES * SQUARES];
+                for square in 0..SQUARES {
+                    for plane in 0..PLANES {
+                        planes[plane * SQUARES + square] = xs[square * PLANES + plane];
+                    }
+                }
+                let mut board = stem.conv3x3(&planes);
+                relu(&mut board);
+                for block in self.blocks.iter() {
+                    let mut inner = block.conv1.conv3x3(&board);
+                    relu(&mut inner);
+                    let mut outer = block.conv2.conv3x3(&inner);
+                    for (y, x) in outer.iter_mut().zip(board.iter()) {
+                        *y += x;
+                    }
+                    relu(&mut outer);
+                    board = outer;
+                }
+                let mut pooled: Vec<f32> = board
+                    .chunks(SQUARES)
+                    .map(|plane| plane.iter().sum::<f32>() / SQUARES as f32)
+                    .collect();
+                pooled.extend_from_slice(&xs[PLANES * SQUARES..]);
+                pooled
+            }
+        };
+
+        for layer in self.hidden.iter() {
+            ys = layer.dense.forward(&ys);
+            if let Some(norm) = &layer.layer_norm {
+                norm.forward(&mut ys);
+            }
+            activate(self.spec.activation, &mut ys);
+        }
+
+        let atoms = self.spec.returns.atoms();
+        match self.spec.head {
+            Head::Plain => self.heads[0].forward(&ys),
+            Head::Dueling => {
+                let value = self.heads[0].forward(&ys);
+                let mut advantage = self.heads[1].forward(&ys);
+                // the advantage is centred on the legal actions only
+                let count = legal.iter().filter(|x| **x != 0).count().max(1) as f32;
+                for (k, v) in value.iter().enumerate() {
+                    let mean = (0..ACTION_SIZE)
+                        .filter(|a| legal[*a] != 0)
+                        .map(|a| advantage[a * atoms + k])
+                        .sum::<f32>()
+                        / count;
+                    for a in 0..ACTION_SIZE {
+                        advantage[a * atoms + k] += v - mean;
+                    }
+                }
+                advantage
+            }
+        }
+    }
+
+    fn distribution(&self, raw: &[f32]) -> Vec<f32> {
+        match self.spec.returns {
+            Returns::Categorical { .. } => {
+                let max = raw.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let exp: Vec<f32> = raw.iter().map(|x| (x - max).exp()).collect();
+                let sum: f32 = exp.iter().sum();
+                exp.into_iter().map(|x| x / sum).collect()
+            }
+            _ => raw.to_vec(),
+        }
+    }
+
+    fn expected(&self, raw: &[f32]) -> Vec<f32> {
+        let returns = self.spec.returns;
+        let support = returns.support();
+        raw.chunks(returns.atoms())
+            .map(|atoms| match returns {
+                Returns::Expected => atoms[0],
+                Returns::Categorical { .. } => self
+                    .distribution(atoms)
+                    .iter()
+                    .zip(support.iter())
+                    .map(|(p, z)| p * z)
+                    .sum(),
+                Returns::Quantile { .. } => atoms.iter().sum::<f32>() / atoms.len() as f32,
+            })
+            .collect()
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        self.write(path, self.iteration)
+    }
+
+    fn write(&self, path: &str, iteration: i64) -> Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        for x in [FORMAT_VERSION, STATE_SIZE as u32, ACTION_SIZE as u32, FEATURE_VERSION] {
+            w.write_all(&x.to_le_bytes())?;
+        }
+        w.write_all(&iteration.to_le_bytes())?;
+        let spec = self.spec.to_json().to_string();
+        w.write_all(&(spec.len() as u32).to_le_bytes())?;
+        w.write_all(spec.as_bytes())?;
+
+        if let Some(stem) = &self.stem {
+            stem.write_to(&mut w)?;
+        }
+        for block in self.blocks.iter() {
+            block.conv1.write_to(&mut w)?;
+            block.conv2.write_to(&mut w)?;
+        }
+        for layer in self.hidden.iter() {
+            layer.dense.write_to(&mut w)?;
+            if let Some(norm) = &layer.layer_norm {
+                write_floats(&mut w, &norm.weight)?;
+                write_floats(&mut w, &norm.bias)?;
+            }
+        }
+        for output in self.heads.iter() {
+            output.write_to(&mut w)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("{} is not an exported network", path);
+        }
+        let version = read_u32(&mut r)?;
+        ensure!(
+            version == FORMAT_VERSION,
+            "exported network format version {} is not supported (expected {})",
+            version,
+            FORMAT_VERSION
+        );
+        let (state_size, action_size, feature_version) =
+            (read_u32(&mut r)? as usize, read_u32(&mut r)? as usize, read_u32(&mut r)?);
+        ensure!(
+            state_size == STATE_SIZE && action_size == ACTION_SIZE && feature_version == FEATURE_VERSION,
+            "the feature encoding has changed: {} was exported with STATE_SIZE={} ACTION_SIZE={} \
+             FEATURE_VERSION={} but this build uses STATE_SIZE={} ACTION_SIZE={} FEATURE_VERSION={}",
+            path,
+            state_size,
+            action_size,
+            feature_version,
+            STATE_SIZE,
+            ACTION_SIZE,
+            FEATURE_VERSION
+        );
+        let mut iteration = [0u8; 8];
+        r.read_exact(&mut iteration)?;
+        let iteration = i64::from_le_bytes(iteration);
+        let mut spec = vec![0u8; read_u32(&mut r)? as usize];
+        r.read_exact(&mut spec)?;
+        let spec = NetworkSpec::from_json(&serde_json::from_slice(&spec)?)?;
+
+        let (mut stem, mut blocks) = (None, Vec::new());
+        if let Encoder::Residual { channels, blocks: count } = spec.encoder {
+            let channels = channels as usize;
+            stem = Some(Dense::read_from(&mut r, PLANES * 9, channels)?);
+            for _ in 0..count {
+                blocks.push(ResidualBlock {
+                    conv1: Dense::read_from(&mut r, channels * 9, channels)?,
+                    conv2: Dense::read_from(&mut r, channels * 9, channels)?,
+                });
+            }
+        }
+        let mut hidden = Vec::with_capacity(spec.depth());
+        let mut width = spec.encoder.width() as usize;
+        for out in spec.widths.iter() {
+            let out = *out as usize;
+            let dense = Dense::read_from(&mut r, width, out)?;
+            let layer_norm = match spec.norm {
+                Norm::Layer => Some(LayerNorm {
+                    weight: read_floats(&mut r, out)?,
+                    bias: read_floats(&mut r, out)?,
+                }),
+                _ => None,
+            };
+            hidden.push(Hidden { dense, layer_norm });
+            width = out;
+        }
+        let atoms = spec.returns.atoms();
+        let heads = match spec.head {
+            Head::Plain => vec![Output::read_from(&mut r, width, atoms)?],
+            Head::Dueling => vec![
+                Output::Dense(Dense::read_from(&mut r, width, atoms)?),
+                Output::read_from(&mut r, width, atoms)?,
+            ],
+        };
+
+        Ok(Self {
+            spec,
+            stem,
+            blocks,
+            hidden,
+            heads,
+            iteration,
+        })
+    }
+}
+
+fn write_floats<W: Write>(w: &mut W, xs: &[f32]) -> Result<()> {
+    w.write_all(&(xs.len() as u32).to_le_bytes())?;
+    for x in xs.iter() {
+        w.write_all(&x.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_floats<R: Read>(r: &mut R, len: usize) -> Result<Vec<f32>> {
+    let file_len = read_u32(r)? as usize;
+    ensure!(file_len == len, "exported tensor has {} values, expected {}", file_len, len);
+    let mut bytes = vec![0u8; 4 * len];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes
+        .chunks(4)
+        .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect())
+}
+
+// acts as a deployed `CerkeAgent`: noise off, with the heads of an ensemble merged into one
+impl Policy for CpuNet {
+    fn acting_q(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<f32>> {
+        self.q_values(batch, legal)
+    }
+
+    fn noisy(&self) -> bool {
+        self.spec.noisy.is_some()
+    }
+
+    fn head_q(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<Vec<f32>>> {
+        self.q_values(batch, legal).into_iter().map(|q| vec![q]).collect()
+    }
+}
+
+impl Brain for CpuNet {
+    fn train(
+        &mut self,
+        _states: &[f32],
+        _legal: &[i8],
+        _actions: &[usize],
+        _targets: &[f32],
+        _weights: &[f32],
+        _masks: &[f32],
+    ) -> Result<(Vec<f32>, TrainMetrics)> {
+        bail!("CpuNet is for inference only; train a QNet and export it")
+    }
+
+    fn forward(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<Vec<Vec<f32>>> {
+        Ok(self.q_values(batch, legal))
+    }
+
+    // the target network is the only one exported, so `double` changes nothing
+    fn greedy_distributions(
+        &self,
+        batch: Vec<&[f32]>,
+        legal: Vec<&[i8]>,
+        _double: bool,
+    ) -> Result<Vec<Option<Vec<(usize, Vec<f32>)>>>> {
+        let atoms = self.spec.returns.atoms();
+        Ok(batch
+            .iter()
+            .zip(legal.iter())
+            .map(|(xs, legal)| {
+                let raw = self.forward_raw(xs, legal);
+                let q = self.expected(&raw);
+                let action = (0..ACTION_SIZE)
+                    .filter(|a| legal[*a] != 0)
+                    .fold(None, |best: Option<usize>, a| match best {
+                        Some(b) if q[b] >= q[a] => Some(b),
+                        _ => Some(a),
+                    })?;
+                Some(vec![(action, self.distribution(&raw[action * atoms..(action + 1) * atoms]))])
+            })
+            .collect())
+    }
+
+    fn forward_distribution(
+        &self,
+        batch: Vec<&[f32]>,
+        legal: Vec<&[i8]>,
+        actions: &[usize],
+    ) -> Result<Vec<Vec<f32>>> {
+        let atoms = self.spec.returns.atoms();
+        Ok(batch
+            .iter()
+            .zip(legal.iter())
+            .zip(actions.iter())
+            .map(|((xs, legal), action)| {
+                let raw = self.forward_raw(xs, legal);
+                self.distribution(&raw[action * atoms..(action + 1) * atoms])
+            })
+            .collect())
+    }
+
+    fn returns(&self) -> Returns {
+        self.spec.returns
+    }
+
+    fn update_hard(&mut self) {}
+
+    fn update_soft(&mut self, _tau: f64) {}
+
+    fn save(&self, name: &String, iteration: i64) -> Result<()> {
+        self.write(name, iteration)
+    }
+
+    fn load(&mut self, name: &String) -> Result<i64> {
+        *self = Self::load(name)?;
+        Ok(self.iteration)
+    }
+}
+
+#[cfg(all(test, feature = "torch"))]
+fn assert_matches_qnet(spec: NetworkSpec) {
+    use super::brain::QNet;
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    let mut qnet = QNet::with_spec(spec);
+    let batch = 4;
+    let states: Vec<f32> = (0..batch * STATE_SIZE)
+        .map(|_| (rng.gen::<f32>() < 0.1) as i32 as f32)
+        .collect();
+    let legal: Vec<i8> = (0..batch * ACTION_SIZE)
+        .map(|_| (rng.gen::<f32>() < 0.05) as i8)
+        .collect();
+    let actions: Vec<usize> = (0..batch).collect();
+    let atoms = qnet.returns().atoms();
+    let targets = vec![1.0 / atoms as f32; batch * qnet.heads() * atoms];
+    let weights = vec![1f32; batch];
+    let masks = vec![1f32; batch * qnet.heads()];
+
+    // a train step moves the batch norm statistics away from their initial values
+    qnet.train(&states, &legal, &actions, &targets, &weights, &masks).unwrap();
+    qnet.update_hard();
+
+    let rows: Vec<&[f32]> = states.chunks(STATE_SIZE).collect();
+    let masks: Vec<&[i8]> = legal.chunks(ACTION_SIZE).collect();
+    let expected = qnet.forward(rows.clone(), masks.clone()).unwrap();
+
+    let file = crate::learn::temp_file::TempFile::new("cpu_net.bin");
+    let path = file.path();
+    qnet.export(7).unwrap().save(path).unwrap();
+    let cpu = CpuNet::load(path).unwrap();
+    assert_eq!(cpu.iteration, 7);
+
+    let actual = cpu.forward(rows, masks).unwrap();
+    for (x, y) in expected.iter().flatten().zip(actual.iter().flatten()) {
+        assert!((x - y).abs() < 1e-3 * x.abs().max(1.0), "{} != {}", x, y);
+    }
+}
+
+#[cfg(feature = "torch")]
+#[test]
+fn test_cpu_net_matches_qnet() {
+    assert_matches_qnet(NetworkSpec::default());
+    assert_matches_qnet(NetworkSpec {
+        encoder: Encoder::Residual {
+            channels: 4,
+            blocks: 1,
+        },
+        widths: vec![32, 16],
+        norm: Norm::Layer,
+        activation: Activation::Gelu,
+        dropout: 0.1,
+        head: Head::Dueling,
+        returns: Returns::Categorical {
+            atoms: 5,
+            v_min: -2.0,
+            v_max: 2.0,
+        },
+        noisy: Some(0.5),
+        ensemble: 1,
+    });
+    assert_matches_qnet(NetworkSpec {
+        widths: vec![16],
+        head: Head::Dueling,
+        ensemble: 3,
+        ..NetworkSpec::default()
+    });
+}
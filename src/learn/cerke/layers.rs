@@ -0,0 +1,529 @@
+use tch::{nn, nn::ModuleT, Kind, Tensor};
+
+use super::network::{Activation, Encoder, Head, NetworkSpec, Norm, Returns, ATOM_RANK, HAND_FEATURES, PLANES, SQUARES};
+use crate::learn::state_to_feature::ACTION_SIZE;
+#[cfg(test)]
+use crate::learn::state_to_feature::STATE_SIZE;
+
+impl Returns {
+    // `raw` is a [batch, action, atom] network output; returns the [batch, action] expected values
+    pub(crate) fn expected(self, raw: &Tensor) -> Tensor {
+        match self {
+            Returns::Expected => raw.squeeze_dim(-1),
+            Returns::Categorical { .. } => {
+                let support = Tensor::of_slice(&self.support()).to(raw.device());
+                (raw.softmax(-1, Kind::Float) * support).sum_dim_intlist(&[-1], false, Kind::Float)
+            }
+            Returns::Quantile { .. } => raw.mean_dim(&[-1], false, Kind::Float),
+        }
+    }
+
+    // probabilities for a categorical output, the outputs themselves otherwise
+    pub(crate) fn distribution(self, raw: &Tensor) -> Tensor {
+        match self {
+            Returns::Categorical { .. } => raw.softmax(-1, Kind::Float),
+            _ => raw.shallow_clone(),
+        }
+    }
+}
+
+impl Activation {
+    fn forward(self, xs: &Tensor) -> Tensor {
+        match self {
+            Activation::Relu => xs.relu(),
+            Activation::LeakyRelu => xs.leaky_relu(),
+            Activation::Tanh => xs.tanh(),
+            Activation::Gelu => xs.gelu(),
+        }
+    }
+}
+
+pub(crate) trait QModule: Send {
+    // `legal` is a 0/1 float mask of the legal actions of each row; noisy layers only perturb
+    // their weights when `noise` is set. returns the [batch, head, action, atom] raw outputs
+    // of every head of the ensemble
+    fn forward_heads(&self, xs: &Tensor, legal: &Tensor, train: bool, noise: bool) -> Tensor;
+
+    // [batch, action, atom] mean of the heads
+    fn forward_q(&self, xs: &Tensor, legal: &Tensor, train: bool, noise: bool) -> Tensor {
+        self.forward_heads(xs, legal, train, noise)
+            .mean_dim(&[1], false, Kind::Float)
+    }
+}
+
+// factorised Gaussian noisy linear layer (Fortunato et al., 2017)
+#[derive(Debug)]
+struct NoisyLinear {
+    weight_mu: Tensor,
+    weight_sigma: Tensor,
+    bias_mu: Tensor,
+    bias_sigma: Tensor,
+}
+
+impl NoisyLinear {
+    fn new(vs: nn::Path, in_dim: i64, out_dim: i64, sigma0: f64) -> Self {
+        let bound = 1.0 / (in_dim as f64).sqrt();
+        let mu = nn::Init::Uniform {
+            lo: -bound,
+            up: bound,
+        };
+        let sigma = nn::Init::Const(sigma0 * bound);
+        Self {
+            weight_mu: vs.var("weight_mu", &[out_dim, in_dim], mu),
+            weight_sigma: vs.var("weight_sigma", &[out_dim, in_dim], sigma),
+            bias_mu: vs.var("bias_mu", &[out_dim], mu),
+            bias_sigma: vs.var("bias_sigma", &[out_dim], sigma),
+        }
+    }
+
+    fn forward(&self, xs: &Tensor, noise: bool) -> Tensor {
+        if !noise {
+            return xs.linear(&self.weight_mu, Some(&self.bias_mu));
+        }
+        let device = self.weight_mu.device();
+        let factor = |n: i64| {
+            let eps = Tensor::randn(&[n], (Kind::Float, device));
+            eps.sign() * eps.abs().sqrt()
+        };
+        let (out_dim, in_dim) = self.weight_mu.size2().unwrap();
+        let (eps_out, eps_in) = (factor(out_dim), factor(in_dim));
+        let weight = &self.weight_mu + &self.weight_sigma * (eps_out.unsqueeze(1) * eps_in.unsqueeze(0));
+        let bias = &self.bias_mu + &self.bias_sigma * eps_out;
+        xs.linear(&weight, Some(&bias))
+    }
+}
+
+enum Dense {
+    Linear(nn::Linear),
+    Noisy(NoisyLinear),
+}
+
+impl Dense {
+    fn new(vs: nn::Path, in_dim: i64, out_dim: i64, noisy: Option<f64>) -> Self {
+        match noisy {
+            Some(sigma0) => Dense::Noisy(NoisyLinear::new(vs, in_dim, out_dim, sigma0)),
+            None => Dense::Linear(nn::linear(vs, in_dim, out_dim, Default::default())),
+        }
+    }
+
+    fn forward(&self, xs: &Tensor, noise: bool) -> Tensor {
+        match self {
+            Dense::Linear(linear) => xs.apply(linear),
+            Dense::Noisy(noisy) => noisy.forward(xs, noise),
+        }
+    }
+}
+
+// the [action, atom] outputs of a distributional head as a low-rank product: the features
+// are projected to `ATOM_RANK` codes of every atom, which a learned embedding of each action
+// mixes, instead of one layer with `ACTION_SIZE * atoms` outputs
+struct FactorisedOutput {
+    code: Dense,
+    // [action, rank]
+    embedding: Tensor,
+    // [action, atom]
+    bias: Tensor,
+}
+
+enum Output {
+    Dense(Dense),
+    Factorised(FactorisedOutput),
+}
+
+impl Output {
+    // expected-value heads keep a plain layer
+    fn new(vs: nn::Path, in_dim: i64, atoms: i64, noisy: Option<f64>) -> Self {
+        if atoms == 1 {
+            return Output::Dense(Dense::new(vs, in_dim, ACTION_SIZE as i64, noisy));
+        }
+        let stdev = 1.0 / (ATOM_RANK as f64).sqrt();
+        Output::Factorised(FactorisedOutput {
+            code: Dense::new(&vs / "code", in_dim, ATOM_RANK * atoms, noisy),
+            embedding: vs.var("embedding", &[ACTION_SIZE as i64, ATOM_RANK], nn::Init::Randn { mean: 0.0, stdev }),
+            bias: vs.zeros("bias", &[ACTION_SIZE as i64, atoms]),
+        })
+    }
+
+    // [batch, action, atom]
+    fn forward(&self, xs: &Tensor, noise: bool) -> Tensor {
+        match self {
+            Output::Dense(dense) => dense.forward(xs, noise).view([-1, ACTION_SIZE as i64, 1]),
+            Output::Factorised(output) => {
+                let atoms = output.bias.size()[1];
+                let code = output.code.forward(xs, noise).view([-1, ATOM_RANK, atoms]);
+                output.embedding.matmul(&code) + &output.bias
+            }
+        }
+    }
+}
+
+struct Hidden {
+    dense: Dense,
+    norm: Option<Box<dyn ModuleT>>,
+}
+
+struct Trunk {
+    encoder: Option<ResidualEncoder>,
+    hidden: Vec<Hidden>,
+    activation: Activation,
+    dropout: f64,
+}
+
+impl Trunk {
+    fn forward(&self, xs: &Tensor, train: bool, noise: bool) -> Tensor {
+        let mut ys = match &self.encoder {
+            Some(encoder) => encoder.forward_t(xs, train),
+            None => xs.shallow_clone(),
+        };
+        for layer in self.hidden.iter() {
+            ys = layer.dense.forward(&ys, noise);
+            if let Some(norm) = &layer.norm {
+                ys = norm.forward_t(&ys, train);
+            }
+            ys = self.activation.forward(&ys);
+            if self.dropout > 0.0 {
+                ys = ys.dropout(self.dropout, train);
+            }
+        }
+        ys
+    }
+}
+
+struct Plain {
+    trunk: Trunk,
+    // one per head
+    outs: Vec<Output>,
+}
+
+impl QModule for Plain {
+    fn forward_heads(&self, xs: &Tensor, _legal: &Tensor, train: bool, noise: bool) -> Tensor {
+        let hidden = self.trunk.forward(xs, train, noise);
+        let heads: Vec<Tensor> = self.outs.iter().map(|out| out.forward(&hidden, noise)).collect();
+        Tensor::stack(&heads, 1)
+    }
+}
+
+struct DuelingHead {
+    value: Dense,
+    advantage: Output,
+}
+
+struct Dueling {
+    trunk: Trunk,
+    heads: Vec<DuelingHead>,
+    atoms: i64,
+}
+
+impl QModule for Dueling {
+    fn forward_heads(&self, xs: &Tensor, legal: &Tensor, train: bool, noise: bool) -> Tensor {
+        let hidden = self.trunk.forward(xs, train, noise);
+        // the advantage is centred on the legal actions only
+        let legal = legal.unsqueeze(-1);
+        let count = legal
+            .sum_dim_intlist(&[1], true, Kind::Float)
+            .clamp_min(1f64);
+        let heads: Vec<Tensor> = self
+            .heads
+            .iter()
+            .map(|head| {
+                let value = head.value.forward(&hidden, noise).view([-1, 1, self.atoms]);
+                let advantage = head.advantage.forward(&hidden, noise);
+                let mean = (&advantage * &legal).sum_dim_intlist(&[1], true, Kind::Float) / &count;
+                value + advantage - mean
+            })
+            .collect();
+        Tensor::stack(&heads, 1)
+    }
+}
+
+fn conv3x3(vs: nn::Path, in_channels: i64, out_channels: i64) -> nn::Conv2D {
+    let config = nn::ConvConfig {
+        padding: 1,
+        bias: false,
+        ..Default::default()
+    };
+    nn::conv2d(vs, in_channels, out_channels, 3, config)
+}
+
+#[derive(Debug)]
+struct ResidualBlock {
+    conv1: nn::Conv2D,
+    bn1: nn::BatchNorm,
+    conv2: nn::Conv2D,
+    bn2: nn::BatchNorm,
+}
+
+impl ResidualBlock {
+    fn new(vs: nn::Path, channels: i64) -> Self {
+        Self {
+            conv1: conv3x3(&vs / "conv1", channels, channels),
+            bn1: nn::batch_norm2d(&vs / "bn1", channels, Default::default()),
+            conv2: conv3x3(&vs / "conv2", channels, channels),
+            bn2: nn::batch_norm2d(&vs / "bn2", channels, Default::default()),
+        }
+    }
+}
+
+impl ModuleT for ResidualBlock {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        let ys = xs
+            .apply(&self.conv1)
+            .apply_t(&self.bn1, train)
+            .relu()
+            .apply(&self.conv2)
+            .apply_t(&self.bn2, train);
+        (ys + xs).relu()
+    }
+}
+
+#[derive(Debug)]
+struct ResidualEncoder {
+    stem: nn::Conv2D,
+    stem_bn: nn::BatchNorm,
+    blocks: Vec<ResidualBlock>,
+}
+
+impl ResidualEncoder {
+    fn new(vs: nn::Path, channels: i64, blocks: usize) -> Self {
+        Self {
+            stem: conv3x3(&vs / "stem", PLANES, channels),
+            stem_bn: nn::batch_norm2d(&vs / "stem_bn", channels, Default::default()),
+            blocks: (0..blocks)
+                .map(|i| ResidualBlock::new(&vs / format!("block{}", i), channels))
+                .collect(),
+        }
+    }
+}
+
+impl ModuleT for ResidualEncoder {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Tensor {
+        // [batch, square, plane] -> [batch, plane, row, column]
+        let board = xs
+            .narrow(1, 0, PLANES * SQUARES)
+            .reshape(&[-1, SQUARES, PLANES])
+            .permute(&[0, 2, 1])
+            .reshape(&[-1, PLANES, 9, 9]);
+        let hands = xs.narrow(1, PLANES * SQUARES, HAND_FEATURES);
+
+        let mut ys = board.apply(&self.stem).apply_t(&self.stem_bn, train).relu();
+        for block in self.blocks.iter() {
+            ys = block.forward_t(&ys, train);
+        }
+        let pooled = ys.mean_dim(&[2, 3], false, Kind::Float);
+        Tensor::cat(&[pooled, hands], 1)
+    }
+}
+
+fn trunk(vs: &nn::Path, spec: &NetworkSpec) -> Trunk {
+    let encoder = match spec.encoder {
+        Encoder::Flat => None,
+        Encoder::Residual { channels, blocks } => {
+            Some(ResidualEncoder::new(vs / "encoder", channels, blocks))
+        }
+    };
+    let mut hidden = Vec::with_capacity(spec.depth());
+    let mut width = spec.encoder.width();
+    for (i, out) in spec.widths.iter().copied().enumerate() {
+        let layer = vs / format!("hidden{}", i);
+        let norm: Option<Box<dyn ModuleT>> = match spec.norm {
+            Norm::None => None,
+            Norm::Batch => Some(Box::new(nn::batch_norm1d(&layer / "norm", out, Default::default()))),
+            Norm::Layer => Some(Box::new(nn::layer_norm(&layer / "norm", vec![out], Default::default()))),
+        };
+        hidden.push(Hidden {
+            dense: Dense::new(&layer / "linear", width, out, spec.noisy),
+            norm,
+        });
+        width = out;
+    }
+    Trunk {
+        encoder,
+        hidden,
+        activation: spec.activation,
+        dropout: spec.dropout,
+    }
+}
+
+// the path `network::head_var` names
+fn head_path<'a>(vs: &nn::Path<'a>, ensemble: usize, h: usize, name: &str) -> nn::Path<'a> {
+    if ensemble == 1 {
+        vs / name
+    } else {
+        &(vs / format!("head{}", h)) / name
+    }
+}
+
+pub(crate) fn q_module(vs: &nn::Path, spec: &NetworkSpec) -> Box<dyn QModule> {
+    let trunk = trunk(vs, spec);
+    let width = spec.output_width();
+    let atoms = spec.returns.atoms() as i64;
+    let path = |h: usize, name: &str| head_path(vs, spec.ensemble, h, name);
+    let output = |h: usize, name: &str| Output::new(path(h, name), width, atoms, spec.noisy);
+    match spec.head {
+        Head::Plain => Box::new(Plain {
+            trunk,
+            outs: (0..spec.ensemble).map(|h| output(h, "out")).collect(),
+        }),
+        Head::Dueling => Box::new(Dueling {
+            trunk,
+            heads: (0..spec.ensemble)
+                .map(|h| DuelingHead {
+                    value: Dense::new(path(h, "value"), width, atoms, spec.noisy),
+                    advantage: output(h, "advantage"),
+                })
+                .collect(),
+            atoms,
+        }),
+    }
+}
+
+pub(crate) trait PolicyValueModule: Send {
+    // log-probabilities over the actions, with the illegal ones pushed far below the legal
+    // ones, and the value of each row
+    fn forward_pv(&self, xs: &Tensor, legal: &Tensor, train: bool) -> (Tensor, Tensor);
+}
+
+struct PolicyValue {
+    trunk: Trunk,
+    policy: Dense,
+    value: Dense,
+}
+
+impl PolicyValueModule for PolicyValue {
+    fn forward_pv(&self, xs: &Tensor, legal: &Tensor, train: bool) -> (Tensor, Tensor) {
+        // noisy layers are only perturbed while training; acting samples from the policy
+        let hidden = self.trunk.forward(xs, train, train);
+        let logits = self.policy.forward(&hidden, train) + (legal - 1.0) * 1e9;
+        let value = self.value.forward(&hidden, train).squeeze_dim(-1);
+        (logits.log_softmax(-1, Kind::Float), value)
+    }
+}
+
+// the trunk of `spec` under a policy head and a scalar value head; the Q-head settings
+// (`head`, `returns`, `ensemble`) do not apply
+pub(crate) fn policy_value_module(vs: &nn::Path, spec: &NetworkSpec) -> Box<dyn PolicyValueModule> {
+    let width = spec.output_width();
+    Box::new(PolicyValue {
+        trunk: trunk(vs, spec),
+        policy: Dense::new(vs / "policy", width, ACTION_SIZE as i64, spec.noisy),
+        value: Dense::new(vs / "value", width, 1, spec.noisy),
+    })
+}
+
+#[test]
+fn test_residual_encoder_shape() {
+    let spec = NetworkSpec {
+        encoder: Encoder::Residual {
+            channels: 8,
+            blocks: 2,
+        },
+        widths: vec![32],
+        ..NetworkSpec::default()
+    };
+    let vs = nn::VarStore::new(tch::Device::Cpu);
+    let net = q_module(&vs.root(), &spec);
+    let xs = Tensor::rand(&[3, STATE_SIZE as i64], (Kind::Float, tch::Device::Cpu));
+    let legal = Tensor::ones(&[3, ACTION_SIZE as i64], (Kind::Float, tch::Device::Cpu));
+    assert_eq!(net.forward_q(&xs, &legal, true, false).size(), vec![3, ACTION_SIZE as i64, 1]);
+}
+
+#[test]
+fn test_noisy_layers() {
+    let spec = NetworkSpec {
+        widths: vec![16],
+        norm: Norm::None,
+        head: Head::Dueling,
+        noisy: Some(0.5),
+        ..NetworkSpec::default()
+    };
+    let vs = nn::VarStore::new(tch::Device::Cpu);
+    let net = q_module(&vs.root(), &spec);
+    let xs = Tensor::rand(&[2, STATE_SIZE as i64], (Kind::Float, tch::Device::Cpu));
+    let legal = Tensor::ones(&[2, ACTION_SIZE as i64], (Kind::Float, tch::Device::Cpu));
+
+    let quiet = net.forward_q(&xs, &legal, false, false);
+    assert!(quiet.equal(&net.forward_q(&xs, &legal, false, false)));
+    assert!(!quiet.equal(&net.forward_q(&xs, &legal, false, true)));
+}
+
+#[test]
+fn test_dueling_advantage_centred_on_legal_actions() {
+    let spec = NetworkSpec {
+        widths: vec![16],
+        norm: Norm::None,
+        ..NetworkSpec::default()
+    };
+    let vs = nn::VarStore::new(tch::Device::Cpu);
+    let root = vs.root();
+    let net = Dueling {
+        trunk: trunk(&root, &spec),
+        heads: vec![DuelingHead {
+            value: Dense::new(&root / "value", 16, 1, None),
+            advantage: Output::new(&root / "advantage", 16, 1, None),
+        }],
+        atoms: 1,
+    };
+    let xs = Tensor::rand(&[2, STATE_SIZE as i64], (Kind::Float, tch::Device::Cpu));
+    // a few legal actions in each row, different ones
+    let legal: Vec<f32> = (0..2 * ACTION_SIZE)
+        .map(|i| (i % ACTION_SIZE / 10 == i / ACTION_SIZE) as i32 as f32)
+        .collect();
+    let legal = Tensor::of_slice(&legal).view([2, ACTION_SIZE as i64]);
+
+    let q = net.forward_q(&xs, &legal, false, false).squeeze_dim(-1);
+    let value = net.heads[0].value.forward(&net.trunk.forward(&xs, false, false), false);
+    let advantage = q - value;
+    let legal_mean = (&advantage * &legal).sum_dim_intlist(&[1], false, Kind::Float)
+        / legal.sum_dim_intlist(&[1], false, Kind::Float);
+    assert!(legal_mean.abs().max().double_value(&[]) < 1e-4);
+    // the illegal actions take no part in the centring
+    let mean = advantage.mean_dim(&[1], false, Kind::Float);
+    assert!(mean.abs().max().double_value(&[]) > 1e-4);
+}
+
+#[test]
+fn test_factorised_distributional_head() {
+    let spec = NetworkSpec {
+        returns: Returns::Categorical {
+            atoms: 51,
+            v_min: -10.0,
+            v_max: 10.0,
+        },
+        ..NetworkSpec::default()
+    };
+    let vs = nn::VarStore::new(tch::Device::Cpu);
+    let net = q_module(&vs.root(), &spec);
+    let head: i64 = vs
+        .variables()
+        .iter()
+        .filter(|(name, _)| name.starts_with("out."))
+        .map(|(_, var)| var.numel() as i64)
+        .sum();
+    // rather than 512 * ACTION_SIZE * 51, about 216M
+    assert!(head < 5_000_000, "the head has {} parameters", head);
+
+    let xs = Tensor::rand(&[2, STATE_SIZE as i64], (Kind::Float, tch::Device::Cpu));
+    let legal = Tensor::ones(&[2, ACTION_SIZE as i64], (Kind::Float, tch::Device::Cpu));
+    assert_eq!(net.forward_q(&xs, &legal, false, false).size(), vec![2, ACTION_SIZE as i64, 51]);
+}
+
+#[test]
+fn test_ensemble_heads() {
+    let spec = NetworkSpec {
+        widths: vec![16],
+        head: Head::Dueling,
+        ensemble: 3,
+        ..NetworkSpec::default()
+    };
+    let vs = nn::VarStore::new(tch::Device::Cpu);
+    let net = q_module(&vs.root(), &spec);
+    assert!(vs.variables().contains_key("head2.advantage.weight"));
+
+    let xs = Tensor::rand(&[2, STATE_SIZE as i64], (Kind::Float, tch::Device::Cpu));
+    let legal = Tensor::ones(&[2, ACTION_SIZE as i64], (Kind::Float, tch::Device::Cpu));
+    let heads = net.forward_heads(&xs, &legal, false, false);
+    assert_eq!(heads.size(), vec![2, 3, ACTION_SIZE as i64, 1]);
+    let mean = heads.mean_dim(&[1], false, Kind::Float);
+    assert!(mean.allclose(&net.forward_q(&xs, &legal, false, false), 1e-5, 1e-6, false));
+}
+
@@ -0,0 +1,59 @@
+use anyhow::Result;
+
+use super::{metrics::TrainMetrics, network::Returns};
+
+// a learner of Q-values: `QNet` trains one with libtorch, `CpuNet` only evaluates an export
+pub trait Brain {
+    // `states` is row-major with one row per action and `legal` holds the matching
+    // legal-action masks; `targets` holds one target distribution of `returns().atoms()`
+    // values per row and head, head after head, and `masks` the 0/1 bootstrap mask of each row,
+    // `heads()` values per row, telling which heads learn from it. returns the TD error of each
+    // row over the heads learning from it and what the step saw
+    fn train(
+        &mut self,
+        states: &[f32],
+        legal: &[i8],
+        actions: &[usize],
+        targets: &[f32],
+        weights: &[f32],
+        masks: &[f32],
+    ) -> Result<(Vec<f32>, TrainMetrics)>;
+    // expected values of every action
+    fn forward(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<Vec<Vec<f32>>>;
+    // number of heads of a bootstrapped ensemble
+    fn heads(&self) -> usize {
+        1
+    }
+    // expected values of every action under each head, indexed [row][head][action]
+    fn forward_heads(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<Vec<Vec<Vec<f32>>>> {
+        Ok(self.forward(batch, legal)?.into_iter().map(|q| vec![q]).collect())
+    }
+    // same as `forward`, with the parameter noise of noisy layers switched on for exploration
+    fn forward_explore(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<Vec<Vec<f32>>> {
+        self.forward(batch, legal)
+    }
+    // greedy legal action of each row under each head with its return distribution under the
+    // same head of the target network, from a single pass of each network: with `double`
+    // (Double DQN) the head of the learning network picks the action, otherwise the head of the
+    // target network. indexed [row][head]; `None` for a row without legal actions
+    fn greedy_distributions(
+        &self,
+        batch: Vec<&[f32]>,
+        legal: Vec<&[i8]>,
+        double: bool,
+    ) -> Result<Vec<Option<Vec<(usize, Vec<f32>)>>>>;
+    // return distribution of `actions[i]` in row i under the target network
+    fn forward_distribution(
+        &self,
+        batch: Vec<&[f32]>,
+        legal: Vec<&[i8]>,
+        actions: &[usize],
+    ) -> Result<Vec<Vec<f32>>>;
+    fn returns(&self) -> Returns;
+    fn update_hard(&mut self);
+    fn update_soft(&mut self, tau: f64);
+    // writes a checkpoint directory recording the train step `iteration`
+    fn save(&self, name: &String, iteration: i64) -> Result<()>;
+    // restores a checkpoint written by `save` and returns its train step
+    fn load(&mut self, name: &String) -> Result<i64>;
+}
@@ -0,0 +1,181 @@
+use serde_json::{json, Value};
+
+// what one `Brain::train` step saw
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrainMetrics {
+    // weighted loss, including the L1 penalty
+    pub loss: f64,
+    // expected value the learning network predicted for the actions taken
+    pub mean_q: f64,
+    pub max_q: f64,
+    // of the absolute TD errors
+    pub td_error_mean: f64,
+    pub td_error_std: f64,
+    pub td_error_max: f64,
+    // gradient norm before clipping
+    pub grad_norm: f64,
+    pub lr: f64,
+    pub batch_size: usize,
+}
+
+impl TrainMetrics {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "loss": self.loss,
+            "mean_q": self.mean_q,
+            "max_q": self.max_q,
+            "td_error_mean": self.td_error_mean,
+            "td_error_std": self.td_error_std,
+            "td_error_max": self.td_error_max,
+            "grad_norm": self.grad_norm,
+            "lr": self.lr,
+            "batch_size": self.batch_size,
+        })
+    }
+}
+
+// the train steps of one iteration taken together
+#[derive(Clone, Debug, PartialEq)]
+pub struct IterationMetrics {
+    // train step count of the agent once the iteration ended
+    pub iteration: i64,
+    pub steps: usize,
+    // means over the steps, except for the maxima, `td_error_std` and `samples`
+    pub loss: f64,
+    pub mean_q: f64,
+    pub max_q: f64,
+    pub td_error_mean: f64,
+    // over the samples of all the steps
+    pub td_error_std: f64,
+    pub td_error_max: f64,
+    pub grad_norm: f64,
+    pub grad_norm_max: f64,
+    pub lr: f64,
+    pub samples: usize,
+}
+
+impl IterationMetrics {
+    pub fn of(iteration: i64, steps: &[TrainMetrics]) -> Self {
+        let mean = |f: fn(&TrainMetrics) -> f64| {
+            if steps.is_empty() {
+                0.0
+            } else {
+                steps.iter().map(f).sum::<f64>() / steps.len() as f64
+            }
+        };
+        let max = |f: fn(&TrainMetrics) -> f64| steps.iter().map(f).fold(0.0, f64::max);
+        let samples: usize = steps.iter().map(|x| x.batch_size).sum();
+        // pooled from the mean and the mean square of each step's absolute TD errors
+        let td_error_std = if samples == 0 {
+            0.0
+        } else {
+            let (sum, sum_sq) = steps.iter().fold((0.0, 0.0), |(sum, sum_sq), x| {
+                let n = x.batch_size as f64;
+                let mean_sq = x.td_error_std * x.td_error_std + x.td_error_mean * x.td_error_mean;
+                (sum + n * x.td_error_mean, sum_sq + n * mean_sq)
+            });
+            let mean = sum / samples as f64;
+            (sum_sq / samples as f64 - mean * mean).max(0.0).sqrt()
+        };
+        Self {
+            iteration,
+            steps: steps.len(),
+            loss: mean(|x| x.loss),
+            mean_q: mean(|x| x.mean_q),
+            max_q: steps.iter().map(|x| x.max_q).fold(f64::NEG_INFINITY, f64::max),
+            td_error_mean: mean(|x| x.td_error_mean),
+            td_error_std,
+            td_error_max: max(|x| x.td_error_max),
+            grad_norm: mean(|x| x.grad_norm),
+            grad_norm_max: max(|x| x.grad_norm),
+            lr: steps.last().map_or(0.0, |x| x.lr),
+            samples,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "iteration": self.iteration,
+            "steps": self.steps,
+            "loss": self.loss,
+            "mean_q": self.mean_q,
+            "max_q": if self.max_q.is_finite() { json!(self.max_q) } else { Value::Null },
+            "td_error_mean": self.td_error_mean,
+            "td_error_std": self.td_error_std,
+            "td_error_max": self.td_error_max,
+            "grad_norm": self.grad_norm,
+            "grad_norm_max": self.grad_norm_max,
+            "lr": self.lr,
+            "samples": self.samples,
+        })
+    }
+}
+
+// what one `PolicyValueBrain::train` step saw
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyValueMetrics {
+    // `policy_loss + value_loss`, plus the L1 penalty
+    pub loss: f64,
+    // cross-entropy of the predicted policy against the target distributions, weighted by the
+    // advantage of each row under `PolicyTarget::Advantage`
+    pub policy_loss: f64,
+    // mean squared error of the predicted values against the outcomes
+    pub value_loss: f64,
+    pub policy_entropy: f64,
+    pub mean_value: f64,
+    // gradient norm before clipping
+    pub grad_norm: f64,
+    pub lr: f64,
+    pub batch_size: usize,
+}
+
+impl PolicyValueMetrics {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "loss": self.loss,
+            "policy_loss": self.policy_loss,
+            "value_loss": self.value_loss,
+            "policy_entropy": self.policy_entropy,
+            "mean_value": self.mean_value,
+            "grad_norm": self.grad_norm,
+            "lr": self.lr,
+            "batch_size": self.batch_size,
+        })
+    }
+}
+
+#[test]
+fn test_iteration_metrics() {
+    let step = |loss: f64, max_q: f64, grad_norm: f64| TrainMetrics {
+        loss,
+        mean_q: 0.5,
+        max_q,
+        td_error_mean: 0.1,
+        td_error_std: 0.0,
+        td_error_max: 0.2,
+        grad_norm,
+        lr: 0.001,
+        batch_size: 100,
+    };
+    let metrics = IterationMetrics::of(7, &[step(1.0, -2.0, 3.0), step(3.0, -1.0, 5.0)]);
+    assert_eq!(metrics.steps, 2);
+    assert_eq!(metrics.loss, 2.0);
+    assert_eq!(metrics.max_q, -1.0);
+    assert_eq!(metrics.grad_norm, 4.0);
+    assert_eq!(metrics.grad_norm_max, 5.0);
+    assert_eq!(metrics.samples, 200);
+    assert!(metrics.td_error_std.abs() < 1e-12);
+
+    // constant errors of 1 in one step and 3 in the other spread by 1 around 2
+    let spread = |td_error_mean: f64| TrainMetrics {
+        td_error_mean,
+        ..step(1.0, 0.0, 1.0)
+    };
+    let metrics = IterationMetrics::of(7, &[spread(1.0), spread(3.0)]);
+    assert!((metrics.td_error_std - 1.0).abs() < 1e-12);
+    assert_eq!(metrics.to_json()["td_error_std"], json!(metrics.td_error_std));
+
+    let empty = IterationMetrics::of(7, &[]);
+    assert_eq!(empty.steps, 0);
+    assert_eq!(empty.to_json()["max_q"], Value::Null);
+}
@@ -1,3 +1,23 @@
+#[cfg(feature = "torch")]
 pub mod agent;
+#[cfg(feature = "torch")]
 pub mod brain;
+pub mod checkpoint;
 pub mod environment;
+pub mod inference;
+#[cfg(feature = "torch")]
+pub mod layers;
+pub mod learner;
+pub mod metrics;
+pub mod network;
+#[cfg(feature = "torch")]
+pub mod optimizer;
+pub mod policy;
+#[cfg(feature = "torch")]
+pub mod policy_value;
+pub mod replay;
+pub mod replay_stats;
+#[cfg(feature = "torch")]
+pub mod service;
+#[cfg(feature = "torch")]
+pub mod torchscript;
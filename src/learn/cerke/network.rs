@@ -0,0 +1,438 @@
+use anyhow::{bail, ensure, Context, Result};
+use serde_json::{json, Value};
+
+use crate::learn::state_to_feature::STATE_SIZE;
+
+// the board block of a feature is 81 squares of 42 one-hot channels, followed by the hand counts
+pub(crate) const PLANES: i64 = 42;
+pub(crate) const SQUARES: i64 = 81;
+pub(crate) const HAND_FEATURES: i64 = STATE_SIZE as i64 - PLANES * SQUARES;
+// rank of the factorised action-by-atom outputs of distributional heads
+pub(crate) const ATOM_RANK: i64 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Head {
+    Plain,
+    // separate state-value and advantage streams
+    Dueling,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoder {
+    // the feature vector as is
+    Flat,
+    // residual CNN over the 9x9 board planes, average-pooled and joined with the hand counts
+    Residual { channels: i64, blocks: usize },
+}
+
+impl Encoder {
+    // width of the encoded features
+    pub fn width(self) -> i64 {
+        match self {
+            Encoder::Flat => STATE_SIZE as i64,
+            Encoder::Residual { channels, .. } => channels + HAND_FEATURES,
+        }
+    }
+
+    fn to_json(self) -> Value {
+        match self {
+            Encoder::Flat => json!({ "kind": "flat" }),
+            Encoder::Residual { channels, blocks } => json!({
+                "kind": "residual",
+                "channels": channels,
+                "blocks": blocks,
+            }),
+        }
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        Ok(match value["kind"].as_str() {
+            Some("flat") => Encoder::Flat,
+            Some("residual") => {
+                let channels = value["channels"]
+                    .as_i64()
+                    .filter(|x| *x > 0)
+                    .context("network spec: encoder `channels` must be a positive integer")?;
+                let blocks = value["blocks"]
+                    .as_u64()
+                    .context("network spec: encoder `blocks` must be an integer")?;
+                Encoder::Residual {
+                    channels,
+                    blocks: blocks as usize,
+                }
+            }
+            _ => bail!("network spec: unknown encoder {}", value),
+        })
+    }
+}
+
+// what the network predicts for each action
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Returns {
+    // the expected return
+    Expected,
+    // probabilities of `atoms` returns evenly spaced over [v_min, v_max] (C51)
+    Categorical { atoms: usize, v_min: f32, v_max: f32 },
+    // returns at the midpoints of `quantiles` equal quantiles (QR-DQN)
+    Quantile { quantiles: usize },
+}
+
+impl Returns {
+    // outputs per action
+    pub fn atoms(self) -> usize {
+        match self {
+            Returns::Expected => 1,
+            Returns::Categorical { atoms, .. } => atoms,
+            Returns::Quantile { quantiles } => quantiles,
+        }
+    }
+
+    // the returns the categorical atoms stand for
+    pub fn support(self) -> Vec<f32> {
+        match self {
+            Returns::Categorical {
+                atoms,
+                v_min,
+                v_max,
+            } => {
+                let delta = (v_max - v_min) / (atoms - 1) as f32;
+                (0..atoms).map(|i| v_min + delta * i as f32).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // target distribution of `reward + discount * Z`, where `next` is the distribution of Z
+    // as `distribution` gives it; a zero discount puts everything on `reward`
+    pub fn project(self, reward: f32, discount: f32, next: &[f32]) -> Vec<f32> {
+        match self {
+            Returns::Expected => vec![reward + discount * next[0]],
+            Returns::Categorical {
+                atoms,
+                v_min,
+                v_max,
+            } => {
+                let delta = (v_max - v_min) / (atoms - 1) as f32;
+                let mut projected = vec![0f32; atoms];
+                for (z, p) in self.support().into_iter().zip(next) {
+                    let b = ((reward + discount * z).max(v_min).min(v_max) - v_min) / delta;
+                    let b = b.min((atoms - 1) as f32);
+                    let (l, u) = (b.floor() as usize, b.ceil() as usize);
+                    if l == u {
+                        projected[l] += p;
+                    } else {
+                        projected[l] += p * (u as f32 - b);
+                        projected[u] += p * (b - l as f32);
+                    }
+                }
+                projected
+            }
+            Returns::Quantile { .. } => next.iter().map(|q| reward + discount * q).collect(),
+        }
+    }
+
+    fn to_json(self) -> Value {
+        match self {
+            Returns::Expected => json!({ "kind": "expected" }),
+            Returns::Categorical {
+                atoms,
+                v_min,
+                v_max,
+            } => json!({
+                "kind": "categorical",
+                "atoms": atoms,
+                "v_min": v_min,
+                "v_max": v_max,
+            }),
+            Returns::Quantile { quantiles } => json!({
+                "kind": "quantile",
+                "quantiles": quantiles,
+            }),
+        }
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        Ok(match value["kind"].as_str() {
+            Some("expected") => Returns::Expected,
+            Some("categorical") => {
+                let atoms = value["atoms"]
+                    .as_u64()
+                    .filter(|x| *x >= 2)
+                    .context("network spec: `atoms` must be an integer of at least 2")?;
+                let (v_min, v_max) = match (value["v_min"].as_f64(), value["v_max"].as_f64()) {
+                    (Some(v_min), Some(v_max)) if v_min < v_max => (v_min as f32, v_max as f32),
+                    _ => bail!("network spec: `v_min` and `v_max` must be numbers with v_min < v_max"),
+                };
+                Returns::Categorical {
+                    atoms: atoms as usize,
+                    v_min,
+                    v_max,
+                }
+            }
+            Some("quantile") => {
+                let quantiles = value["quantiles"]
+                    .as_u64()
+                    .filter(|x| *x >= 1)
+                    .context("network spec: `quantiles` must be a positive integer")?;
+                Returns::Quantile {
+                    quantiles: quantiles as usize,
+                }
+            }
+            _ => bail!("network spec: unknown returns {}", value),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Norm {
+    None,
+    Batch,
+    Layer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Activation {
+    Relu,
+    LeakyRelu,
+    Tanh,
+    Gelu,
+}
+
+// architecture of a Q-network: `widths` are the hidden layers after the encoder,
+// each followed by the normalisation, the activation and the dropout
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkSpec {
+    pub encoder: Encoder,
+    pub widths: Vec<i64>,
+    pub norm: Norm,
+    pub activation: Activation,
+    pub dropout: f64,
+    pub head: Head,
+    pub returns: Returns,
+    // initial noise scale of factorised noisy linear layers replacing the linear ones
+    pub noisy: Option<f64>,
+    // number of heads of a bootstrapped ensemble sharing the trunk, 1 for a single network
+    pub ensemble: usize,
+}
+
+impl Default for NetworkSpec {
+    fn default() -> Self {
+        Self {
+            encoder: Encoder::Flat,
+            widths: vec![512, 512],
+            norm: Norm::Batch,
+            activation: Activation::Relu,
+            dropout: 0.0,
+            head: Head::Plain,
+            returns: Returns::Expected,
+            noisy: None,
+            ensemble: 1,
+        }
+    }
+}
+
+fn name_of<T: Copy + PartialEq>(names: &[(&'static str, T)], x: T) -> &'static str {
+    names.iter().find(|(_name, y)| *y == x).unwrap().0
+}
+
+fn parse_name<T: Copy>(names: &[(&'static str, T)], value: &Value, field: &str) -> Result<T> {
+    let name = value[field]
+        .as_str()
+        .with_context(|| format!("network spec: `{}` must be a string", field))?;
+    match names.iter().find(|(x, _)| *x == name) {
+        Some((_, x)) => Ok(*x),
+        None => bail!("network spec: unknown {} `{}`", field, name),
+    }
+}
+
+const HEADS: [(&str, Head); 2] = [("plain", Head::Plain), ("dueling", Head::Dueling)];
+const NORMS: [(&str, Norm); 3] = [
+    ("none", Norm::None),
+    ("batch", Norm::Batch),
+    ("layer", Norm::Layer),
+];
+const ACTIVATIONS: [(&str, Activation); 4] = [
+    ("relu", Activation::Relu),
+    ("leaky_relu", Activation::LeakyRelu),
+    ("tanh", Activation::Tanh),
+    ("gelu", Activation::Gelu),
+];
+
+impl NetworkSpec {
+    // `depth` hidden layers of the same `width`
+    pub fn uniform(width: i64, depth: usize) -> Self {
+        Self {
+            widths: vec![width; depth],
+            ..Self::default()
+        }
+    }
+
+    // residual CNN board encoder followed by one hidden layer
+    pub fn residual(channels: i64, blocks: usize) -> Self {
+        Self {
+            encoder: Encoder::Residual { channels, blocks },
+            widths: vec![256],
+            ..Self::default()
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.widths.len()
+    }
+
+    // width of the features the head is built on
+    pub fn output_width(&self) -> i64 {
+        self.widths.last().copied().unwrap_or_else(|| self.encoder.width())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "encoder": self.encoder.to_json(),
+            "widths": self.widths,
+            "norm": name_of(&NORMS, self.norm),
+            "activation": name_of(&ACTIVATIONS, self.activation),
+            "dropout": self.dropout,
+            "head": name_of(&HEADS, self.head),
+            "returns": self.returns.to_json(),
+            "noisy": self.noisy,
+            "ensemble": self.ensemble,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let widths = value["widths"]
+            .as_array()
+            .context("network spec: `widths` must be an array")?
+            .iter()
+            .map(|x| match x.as_i64() {
+                Some(w) if w > 0 => Ok(w),
+                _ => bail!("network spec: width {} is not a positive integer", x),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let dropout = value["dropout"]
+            .as_f64()
+            .context("network spec: `dropout` must be a number")?;
+        ensure!(
+            (0.0..1.0).contains(&dropout),
+            "network spec: dropout {} is not in [0, 1)",
+            dropout
+        );
+        let encoder = Encoder::from_json(&value["encoder"])?;
+        let noisy = match value.get("noisy").context("network spec: `noisy` is missing")? {
+            Value::Null => None,
+            sigma => Some(
+                sigma
+                    .as_f64()
+                    .filter(|x| *x > 0.0)
+                    .context("network spec: `noisy` must be null or a positive number")?,
+            ),
+        };
+        let returns = Returns::from_json(&value["returns"])?;
+        let ensemble = value["ensemble"]
+            .as_u64()
+            .filter(|x| *x >= 1)
+            .context("network spec: `ensemble` must be a positive integer")? as usize;
+        // the heads are averaged as raw outputs, which only means something for expected values
+        ensure!(
+            ensemble == 1 || returns == Returns::Expected,
+            "network spec: an ensemble of {} heads needs expected returns",
+            ensemble
+        );
+        Ok(Self {
+            encoder,
+            widths,
+            norm: parse_name(&NORMS, value, "norm")?,
+            activation: parse_name(&ACTIVATIONS, value, "activation")?,
+            dropout,
+            head: parse_name(&HEADS, value, "head")?,
+            returns,
+            noisy,
+            ensemble,
+        })
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(&self.to_json())?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read network spec {}", path))?;
+        Self::from_json(&serde_json::from_str(&text)?)
+    }
+}
+
+// variable name of the head layer `name` of head `h`: ensemble members keep theirs under
+// `head{h}`, a single network at the root
+pub(crate) fn head_var(ensemble: usize, h: usize, name: &str) -> String {
+    if ensemble == 1 {
+        name.to_string()
+    } else {
+        format!("head{}.{}", h, name)
+    }
+}
+
+#[test]
+fn test_network_spec_json() {
+    let spec = NetworkSpec {
+        encoder: Encoder::Residual {
+            channels: 64,
+            blocks: 6,
+        },
+        widths: vec![1024, 256, 256],
+        norm: Norm::Layer,
+        activation: Activation::Gelu,
+        dropout: 0.1,
+        head: Head::Dueling,
+        noisy: Some(0.5),
+        returns: Returns::Categorical {
+            atoms: 51,
+            v_min: -40.0,
+            v_max: 40.0,
+        },
+        ensemble: 1,
+    };
+    assert_eq!(NetworkSpec::from_json(&spec.to_json()).unwrap(), spec);
+    assert_eq!(NetworkSpec::uniform(512, 2), NetworkSpec::default());
+
+    let mut value = spec.to_json();
+    value["norm"] = json!("group");
+    assert!(NetworkSpec::from_json(&value).is_err());
+    value["norm"] = json!("none");
+    value["dropout"] = json!(1.5);
+    assert!(NetworkSpec::from_json(&value).is_err());
+
+    // every field is required
+    for field in ["encoder", "noisy", "returns", "ensemble"] {
+        let mut value = NetworkSpec::default().to_json();
+        value.as_object_mut().unwrap().remove(field);
+        assert!(NetworkSpec::from_json(&value).is_err(), "a spec without `{}` was accepted", field);
+    }
+
+    let mut value = spec.to_json();
+    value["ensemble"] = json!(5);
+    assert!(NetworkSpec::from_json(&value).is_err());
+    value["returns"] = json!({ "kind": "expected" });
+    assert_eq!(NetworkSpec::from_json(&value).unwrap().ensemble, 5);
+}
+
+#[test]
+fn test_returns_project() {
+    let categorical = Returns::Categorical {
+        atoms: 5,
+        v_min: -2.0,
+        v_max: 2.0,
+    };
+    assert_eq!(categorical.support(), vec![-2.0, -1.0, 0.0, 1.0, 2.0]);
+    // shifted by half an atom, clipped at the edges
+    let projected = categorical.project(0.5, 1.0, &[0.0, 0.0, 0.5, 0.0, 0.5]);
+    assert_eq!(projected, vec![0.0, 0.0, 0.25, 0.25, 0.5]);
+    // a terminal transition puts all the mass on the reward
+    let projected = categorical.project(-1.0, 0.0, &[0.5, 0.0, 0.0, 0.25, 0.25]);
+    assert_eq!(projected, vec![0.0, 1.0, 0.0, 0.0, 0.0]);
+
+    let quantile = Returns::Quantile { quantiles: 3 };
+    assert_eq!(quantile.project(1.0, 0.5, &[-2.0, 0.0, 4.0]), vec![0.0, 1.0, 3.0]);
+    assert_eq!(Returns::Expected.project(1.0, 0.5, &[4.0]), vec![3.0]);
+}
@@ -0,0 +1,571 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde_json::{json, Value};
+use tch::{nn::VarStore, Kind, Tensor};
+
+fn number(value: &Value, field: &str) -> Result<f64> {
+    value[field]
+        .as_f64()
+        .with_context(|| format!("optimizer: `{}` must be a number", field))
+}
+
+fn integer(value: &Value, field: &str) -> Result<u64> {
+    value[field]
+        .as_u64()
+        .with_context(|| format!("optimizer: `{}` must be an integer", field))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptimizerKind {
+    Adam { beta1: f64, beta2: f64, eps: f64 },
+    // Adam with the weight decay applied to the weights rather than to the gradient
+    AdamW { beta1: f64, beta2: f64, eps: f64 },
+    Sgd { momentum: f64, nesterov: bool },
+    RmsProp { alpha: f64, eps: f64 },
+}
+
+impl OptimizerKind {
+    fn to_json(self) -> Value {
+        match self {
+            OptimizerKind::Adam { beta1, beta2, eps } => json!({
+                "kind": "adam",
+                "beta1": beta1,
+                "beta2": beta2,
+                "eps": eps,
+            }),
+            OptimizerKind::AdamW { beta1, beta2, eps } => json!({
+                "kind": "adamw",
+                "beta1": beta1,
+                "beta2": beta2,
+                "eps": eps,
+            }),
+            OptimizerKind::Sgd { momentum, nesterov } => json!({
+                "kind": "sgd",
+                "momentum": momentum,
+                "nesterov": nesterov,
+            }),
+            OptimizerKind::RmsProp { alpha, eps } => json!({
+                "kind": "rmsprop",
+                "alpha": alpha,
+                "eps": eps,
+            }),
+        }
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        Ok(match value["kind"].as_str() {
+            Some("adam") => OptimizerKind::Adam {
+                beta1: number(value, "beta1")?,
+                beta2: number(value, "beta2")?,
+                eps: number(value, "eps")?,
+            },
+            Some("adamw") => OptimizerKind::AdamW {
+                beta1: number(value, "beta1")?,
+                beta2: number(value, "beta2")?,
+                eps: number(value, "eps")?,
+            },
+            Some("sgd") => OptimizerKind::Sgd {
+                momentum: number(value, "momentum")?,
+                nesterov: value["nesterov"]
+                    .as_bool()
+                    .context("optimizer: `nesterov` must be a boolean")?,
+            },
+            Some("rmsprop") => OptimizerKind::RmsProp {
+                alpha: number(value, "alpha")?,
+                eps: number(value, "eps")?,
+            },
+            _ => bail!("optimizer: unknown kind {}", value["kind"]),
+        })
+    }
+}
+
+impl Default for OptimizerKind {
+    fn default() -> Self {
+        OptimizerKind::Adam {
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decay {
+    Constant,
+    // from the base learning rate down to `min_factor` of it over `steps` steps
+    Cosine { steps: u64, min_factor: f64 },
+    // multiplied by `gamma` every `every` steps
+    Step { every: u64, gamma: f64 },
+}
+
+// learning-rate multiplier: a linear warmup over the first `warmup` steps, then `decay`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Schedule {
+    pub warmup: u64,
+    pub decay: Decay,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            warmup: 0,
+            decay: Decay::Constant,
+        }
+    }
+}
+
+impl Schedule {
+    // multiplier of the `step`-th step, counting from 1
+    pub fn factor(&self, step: u64) -> f64 {
+        if step <= self.warmup {
+            return step as f64 / self.warmup as f64;
+        }
+        let step = step - self.warmup;
+        match self.decay {
+            Decay::Constant => 1.0,
+            Decay::Cosine { steps, min_factor } => {
+                let progress = (step as f64 / steps.max(1) as f64).min(1.0);
+                min_factor + (1.0 - min_factor) * 0.5 * (1.0 + (std::f64::consts::PI * progress).cos())
+            }
+            Decay::Step { every, gamma } => gamma.powi((step / every.max(1)) as i32),
+        }
+    }
+
+    fn to_json(self) -> Value {
+        let decay = match self.decay {
+            Decay::Constant => json!({ "kind": "constant" }),
+            Decay::Cosine { steps, min_factor } => json!({
+                "kind": "cosine",
+                "steps": steps,
+                "min_factor": min_factor,
+            }),
+            Decay::Step { every, gamma } => json!({
+                "kind": "step",
+                "every": every,
+                "gamma": gamma,
+            }),
+        };
+        json!({ "warmup": self.warmup, "decay": decay })
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        let decay = &value["decay"];
+        let decay = match decay["kind"].as_str() {
+            Some("constant") => Decay::Constant,
+            Some("cosine") => Decay::Cosine {
+                steps: integer(decay, "steps")?,
+                min_factor: number(decay, "min_factor")?,
+            },
+            Some("step") => Decay::Step {
+                every: integer(decay, "every")?,
+                gamma: number(decay, "gamma")?,
+            },
+            _ => bail!("optimizer: unknown decay {}", decay),
+        };
+        Ok(Self {
+            warmup: integer(value, "warmup")?,
+            decay,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OptimizerConfig {
+    pub kind: OptimizerKind,
+    // base learning rate, scaled by `schedule`
+    pub lr: f64,
+    pub weight_decay: f64,
+    pub schedule: Schedule,
+    // rescale the gradients so that their global norm stays under this
+    pub clip_grad_norm: Option<f64>,
+    // weight of the sum of absolute weights added to the loss
+    pub l1: f64,
+}
+
+impl Default for OptimizerConfig {
+    fn default() -> Self {
+        Self {
+            kind: OptimizerKind::default(),
+            lr: 0.00025,
+            weight_decay: 0.0,
+            schedule: Schedule::default(),
+            clip_grad_norm: None,
+            l1: 0.0,
+        }
+    }
+}
+
+impl OptimizerConfig {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "kind": self.kind.to_json(),
+            "lr": self.lr,
+            "weight_decay": self.weight_decay,
+            "schedule": self.schedule.to_json(),
+            "clip_grad_norm": self.clip_grad_norm,
+            "l1": self.l1,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let clip_grad_norm = match &value["clip_grad_norm"] {
+            Value::Null => None,
+            x => Some(
+                x.as_f64()
+                    .context("optimizer: `clip_grad_norm` must be null or a number")?,
+            ),
+        };
+        Ok(Self {
+            kind: OptimizerKind::from_json(&value["kind"])?,
+            lr: number(value, "lr")?,
+            weight_decay: number(value, "weight_decay")?,
+            schedule: Schedule::from_json(&value["schedule"])?,
+            clip_grad_norm,
+            l1: number(value, "l1")?,
+        })
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(&self.to_json())?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read optimizer config {}", path))?;
+        Self::from_json(&serde_json::from_str(&text)?)
+    }
+}
+
+struct Param {
+    name: String,
+    var: Tensor,
+    // under weight decay and L1
+    decays: bool,
+    // first and second moment estimates for Adam; the momentum buffer for SGD and the
+    // running mean of squared gradients for RMSProp
+    m: Tensor,
+    v: Tensor,
+}
+
+// optimizer over the trainable variables of a `VarStore` whose state can be checkpointed,
+// which the optimizers of tch do not allow
+pub struct Optimizer {
+    config: OptimizerConfig,
+    params: Vec<Param>,
+    steps: u64,
+}
+
+impl Optimizer {
+    pub fn new(vs: &VarStore, config: OptimizerConfig) -> Self {
+        let mut params: Vec<Param> = vs
+            .variables()
+            .into_iter()
+            .filter(|(_name, var)| var.requires_grad())
+            .map(|(name, var)| Param {
+                decays: decays(&name, &var),
+                name,
+                m: var.zeros_like(),
+                v: var.zeros_like(),
+                var,
+            })
+            .collect();
+        params.sort_by(|x, y| x.name.cmp(&y.name));
+        Self {
+            config,
+            params,
+            steps: 0,
+        }
+    }
+
+    pub fn config(&self) -> &OptimizerConfig {
+        &self.config
+    }
+
+    // learning rate of the next step
+    pub fn lr(&self) -> f64 {
+        self.config.lr * self.config.schedule.factor(self.steps + 1)
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // the L1 term to add to the loss, if any
+    pub fn l1_penalty(&self) -> Option<Tensor> {
+        if self.config.l1 <= 0.0 {
+            return None;
+        }
+        self.params
+            .iter()
+            .filter(|param| param.decays)
+            .map(|param| param.var.abs().sum(Kind::Float))
+            .reduce(|x, y| x + y)
+            .map(|sum| sum * self.config.l1)
+    }
+
+    pub fn zero_grad(&mut self) {
+        for param in self.params.iter() {
+            let mut grad = param.var.grad();
+            if grad.defined() {
+                let _ = grad.zero_();
+            }
+        }
+    }
+
+    // returns the gradient norm before clipping
+    pub fn backward_step(&mut self, loss: &Tensor) -> f64 {
+        self.zero_grad();
+        loss.backward();
+        self.step()
+    }
+
+    fn grad_norm(&self) -> f64 {
+        self.params
+            .iter()
+            .map(|param| param.var.grad())
+            .filter(|grad| grad.defined())
+            .map(|grad| f64::from(grad.square().sum(Kind::Float)))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    // returns the gradient norm before clipping
+    pub fn step(&mut self) -> f64 {
+        let norm = self.grad_norm();
+        let lr = self.lr();
+        self.steps += 1;
+        let t = self.steps as i32;
+        let OptimizerConfig {
+            kind,
+            weight_decay,
+            clip_grad_norm,
+            ..
+        } = self.config;
+        let scale = match clip_grad_norm {
+            Some(max) if norm > max => max / (norm + 1e-6),
+            _ => 1.0,
+        };
+
+        tch::no_grad(|| {
+            for param in self.params.iter_mut() {
+                let grad = param.var.grad();
+                if !grad.defined() {
+                    continue;
+                }
+                let mut grad = grad * scale;
+                let mut weight = param.var.shallow_clone();
+                if weight_decay > 0.0 && param.decays {
+                    if let OptimizerKind::AdamW { .. } = kind {
+                        weight = &weight * (1.0 - lr * weight_decay);
+                    } else {
+                        grad = grad + &param.var * weight_decay;
+                    }
+                }
+                let updated = match kind {
+                    OptimizerKind::Adam { beta1, beta2, eps } | OptimizerKind::AdamW { beta1, beta2, eps } => {
+                        let m = &param.m * beta1 + &grad * (1.0 - beta1);
+                        let v = &param.v * beta2 + &grad * &grad * (1.0 - beta2);
+                        let m_hat = &m / (1.0 - beta1.powi(t));
+                        let v_hat = &v / (1.0 - beta2.powi(t));
+                        param.m.copy_(&m);
+                        param.v.copy_(&v);
+                        weight - m_hat / (v_hat.sqrt() + eps) * lr
+                    }
+                    OptimizerKind::Sgd { momentum, nesterov } => {
+                        let m = &param.m * momentum + &grad;
+                        let direction = if nesterov { &grad + &m * momentum } else { m.shallow_clone() };
+                        param.m.copy_(&m);
+                        weight - direction * lr
+                    }
+                    OptimizerKind::RmsProp { alpha, eps } => {
+                        let v = &param.v * alpha + &grad * &grad * (1.0 - alpha);
+                        param.v.copy_(&v);
+                        weight - &grad / (v.sqrt() + eps) * lr
+                    }
+                };
+                param.var.copy_(&updated);
+            }
+        });
+        norm
+    }
+
+    // configuration and step count; the moments go to `save_state`
+    pub fn to_json(&self) -> Value {
+        json!({
+            "config": self.config.to_json(),
+            "steps": self.steps,
+        })
+    }
+
+    pub fn save_state(&self, path: &Path) -> Result<()> {
+        let mut named = Vec::with_capacity(2 * self.params.len());
+        for param in self.params.iter() {
+            named.push((format!("m.{}", param.name), param.m.shallow_clone()));
+            named.push((format!("v.{}", param.name), param.v.shallow_clone()));
+        }
+        Tensor::save_multi(&named, path)?;
+        Ok(())
+    }
+
+    // restores what `to_json` and `save_state` wrote for an optimizer over the same variables
+    pub fn load(&mut self, meta: &Value, path: &Path) -> Result<()> {
+        let config = OptimizerConfig::from_json(&meta["config"])?;
+        let steps = integer(meta, "steps")?;
+
+        let mut saved: HashMap<String, Tensor> = Tensor::load_multi(path)
+            .with_context(|| format!("cannot read optimizer state {}", path.display()))?
+            .into_iter()
+            .collect();
+        ensure!(
+            saved.len() == 2 * self.params.len(),
+            "optimizer state {} has {} tensors, expected {}",
+            path.display(),
+            saved.len(),
+            2 * self.params.len()
+        );
+        tch::no_grad(|| -> Result<()> {
+            for param in self.params.iter_mut() {
+                for (prefix, moment) in [("m", &mut param.m), ("v", &mut param.v)] {
+                    let name = format!("{}.{}", prefix, param.name);
+                    let value = saved
+                        .remove(&name)
+                        .with_context(|| format!("optimizer state {} lacks {}", path.display(), name))?;
+                    ensure!(
+                        value.size() == moment.size(),
+                        "optimizer state {}: {} has shape {:?}, expected {:?}",
+                        path.display(),
+                        name,
+                        value.size(),
+                        moment.size()
+                    );
+                    moment.copy_(&value.to_kind(Kind::Float));
+                }
+            }
+            Ok(())
+        })?;
+
+        self.config = config;
+        self.steps = steps;
+        Ok(())
+    }
+}
+
+// biases and the weights and biases of normalisations, the 1-d variables, are left out of
+// weight decay and L1; so is the [action, atom] bias of a factorised head
+fn decays(name: &str, var: &Tensor) -> bool {
+    let leaf = name.rsplit('.').next().unwrap_or(name);
+    var.dim() > 1 && !leaf.starts_with("bias")
+}
+
+// a 2x2 `layer.weight` and a `layer.bias` of 2, all ones, after `steps` steps in which every
+// gradient of the loss is `grad`
+#[cfg(test)]
+fn stepped(config: OptimizerConfig, grad: f64, steps: usize) -> (Vec<f32>, Vec<f32>) {
+    let vs = VarStore::new(tch::Device::Cpu);
+    let layer = &vs.root() / "layer";
+    let weight = layer.var("weight", &[2, 2], tch::nn::Init::Const(1.0));
+    let bias = layer.var("bias", &[2], tch::nn::Init::Const(1.0));
+    let mut opt = Optimizer::new(&vs, config);
+    for _ in 0..steps {
+        let loss = (weight.sum(Kind::Float) + bias.sum(Kind::Float)) * grad;
+        opt.backward_step(&loss);
+    }
+    (
+        Vec::<f32>::from(weight.detach().flatten(0, -1)),
+        Vec::<f32>::from(bias.detach()),
+    )
+}
+
+#[cfg(test)]
+fn assert_close(xs: &[f32], expected: f32) {
+    for x in xs {
+        assert!((x - expected).abs() < 1e-5, "{} != {}", x, expected);
+    }
+}
+
+#[test]
+fn test_sgd_step() {
+    let sgd = |momentum, nesterov, weight_decay| OptimizerConfig {
+        kind: OptimizerKind::Sgd { momentum, nesterov },
+        lr: 0.1,
+        weight_decay,
+        ..OptimizerConfig::default()
+    };
+    // m = 2, then 0.9 * 2 + 2 = 3.8
+    let (weight, bias) = stepped(sgd(0.9, false, 0.0), 2.0, 2);
+    assert_close(&weight, 1.0 - 0.1 * 2.0 - 0.1 * 3.8);
+    assert_close(&bias, 1.0 - 0.1 * 2.0 - 0.1 * 3.8);
+    // the step looks ahead: 2 + 0.9 * 2
+    let (weight, _) = stepped(sgd(0.9, true, 0.0), 2.0, 1);
+    assert_close(&weight, 1.0 - 0.1 * 3.8);
+    // weight decay shrinks the weight only
+    let (weight, bias) = stepped(sgd(0.0, false, 0.5), 0.0, 1);
+    assert_close(&weight, 1.0 - 0.1 * 0.5);
+    assert_close(&bias, 1.0);
+}
+
+#[test]
+fn test_rmsprop_step() {
+    let config = OptimizerConfig {
+        kind: OptimizerKind::RmsProp { alpha: 0.9, eps: 1e-8 },
+        lr: 0.1,
+        ..OptimizerConfig::default()
+    };
+    // the running mean of squared gradients is 0.1 * 4, then 0.9 * 0.4 + 0.1 * 4
+    let (weight, bias) = stepped(config, 2.0, 2);
+    let expected = 1.0 - 0.1 * 2.0 / 0.4f32.sqrt() - 0.1 * 2.0 / 0.76f32.sqrt();
+    assert_close(&weight, expected);
+    assert_close(&bias, expected);
+}
+
+#[test]
+fn test_l1_penalty() {
+    let vs = VarStore::new(tch::Device::Cpu);
+    let layer = &vs.root() / "layer";
+    let _weight = layer.var("weight", &[2, 2], tch::nn::Init::Const(-1.0));
+    let _bias = layer.var("bias", &[2], tch::nn::Init::Const(1.0));
+    let opt = Optimizer::new(
+        &vs,
+        OptimizerConfig {
+            l1: 0.5,
+            ..OptimizerConfig::default()
+        },
+    );
+    // the bias takes no part
+    assert_eq!(f64::from(opt.l1_penalty().unwrap()), 2.0);
+}
+
+#[test]
+fn test_schedule() {
+    let schedule = Schedule {
+        warmup: 10,
+        decay: Decay::Cosine {
+            steps: 100,
+            min_factor: 0.1,
+        },
+    };
+    assert_eq!(schedule.factor(5), 0.5);
+    assert_eq!(schedule.factor(10), 1.0);
+    assert!((schedule.factor(60) - 0.55).abs() < 1e-9);
+    assert!((schedule.factor(1000) - 0.1).abs() < 1e-9);
+
+    let schedule = Schedule {
+        warmup: 0,
+        decay: Decay::Step { every: 10, gamma: 0.5 },
+    };
+    assert_eq!(schedule.factor(9), 1.0);
+    assert_eq!(schedule.factor(25), 0.25);
+
+    let config = OptimizerConfig {
+        kind: OptimizerKind::Sgd {
+            momentum: 0.9,
+            nesterov: true,
+        },
+        schedule,
+        clip_grad_norm: Some(10.0),
+        ..OptimizerConfig::default()
+    };
+    assert_eq!(OptimizerConfig::from_json(&config.to_json()).unwrap(), config);
+}
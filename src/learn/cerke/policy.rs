@@ -0,0 +1,289 @@
+use std::error::Error;
+
+use cetkaik_full_state_transition::{Config, message::{AfterHalfAcceptance, PureMove}, state::{self, Phase}};
+use rand::{prelude::SliceRandom, thread_rng};
+use rand_distr::Distribution;
+
+use super::environment::{Action, CerkeEnv, Environment};
+use crate::learn::state_to_feature::{
+    afterhalf_candidates_to_mask, candidates_to_mask, get_after_half_candidate_by_index,
+    get_candidate_by_index, get_tymok_candidate_by_index, phase_to_mask, state_to_feature,
+    tymok_mask, ACTION_SIZE,
+};
+
+// chooses moves from the Q-values of `acting_q`
+pub trait Policy {
+    fn acting_q(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<f32>>;
+    // noisy networks explore through their own noise and act greedily
+    fn noisy(&self) -> bool;
+    // values of every action under each head of an ensemble, indexed [row][head][action]
+    fn head_q(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<Vec<f32>>>;
+    // when set, parallel selection samples an action with probability proportional to
+    // `exp(q / temperature)` instead of acting epsilon-greedily
+    fn temperature(&self) -> Option<f32> {
+        None
+    }
+
+    // values of head `heads[i]` for row i
+    fn acting_q_of_heads(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>, heads: &[usize]) -> Vec<Vec<f32>> {
+        self.head_q(batch, legal)
+            .into_iter()
+            .zip(heads)
+            .map(|(mut q, head)| q.swap_remove(*head))
+            .collect()
+    }
+
+    // how much the heads of an ensemble disagree on each action of `state`: the standard
+    // deviation of their values, 0 for an illegal action or a single network
+    fn uncertainty(&self, state: &Phase) -> Vec<f32> {
+        let state_vec = state_to_feature(state);
+        let mask = phase_to_mask(state);
+        let heads = self.head_q(vec![&state_vec[..]], vec![&mask[..]]).pop().unwrap();
+        let n = heads.len() as f32;
+        (0..ACTION_SIZE)
+            .map(|i| {
+                if mask[i] == 0 {
+                    return 0.0;
+                }
+                let mean = heads.iter().map(|q| q[i]).sum::<f32>() / n;
+                (heads.iter().map(|q| (q[i] - mean) * (q[i] - mean)).sum::<f32>() / n).sqrt()
+            })
+            .collect()
+    }
+
+    fn select_move(&self, state: &state::A) -> Result<(PureMove, usize), Box<dyn Error>> {
+        let (hop1zuo1_candidates, candidates) = state.get_candidates(Config::cerke_online_alpha());
+        let mask = candidates_to_mask(&hop1zuo1_candidates, &candidates);
+        let state_vec = state_to_feature(&Phase::Start(state.clone()));
+
+        let res = self
+            .acting_q(vec![&state_vec[..]], vec![&mask[..]])
+            .pop()
+            .unwrap();
+
+        // deployed play is greedy unless the policy asks for a softmax; noisy networks
+        // explore through their own noise
+        let max_index = match self.temperature() {
+            Some(temperature) => sample_softmax(&res, &mask, temperature),
+            None => (0..ACTION_SIZE)
+                .filter(|i| mask[*i] == 1)
+                .reduce(|x, y| if res[y] > res[x] { y } else { x })
+                .unwrap(),
+        };
+
+        Ok((
+            get_candidate_by_index(max_index, &hop1zuo1_candidates, &candidates),
+            max_index,
+        ))
+    }
+
+    fn select_stepped(
+        &self,
+        state: &state::C,
+    ) -> Result<(AfterHalfAcceptance, usize), Box<dyn Error>> {
+        let candidates = state.get_candidates(Config::cerke_online_alpha());
+        let mask = afterhalf_candidates_to_mask(&candidates);
+        let state_vec = state_to_feature(&Phase::AfterCiurl(state.clone()));
+
+        let res = self
+            .acting_q(vec![&state_vec[..]], vec![&mask[..]])
+            .pop()
+            .unwrap();
+
+        let mut max_value = f32::NEG_INFINITY;
+        let mut max_index = 0;
+
+        if self.noisy() || rand::random::<f32>() < 0.98f32 {
+            for (i, v) in res.iter().enumerate() {
+                if mask[i] == 1 && max_value < *v {
+                    max_index = i;
+                    max_value = *v;
+                }
+            }
+        } else {
+            let mut candidates = Vec::new();
+            for (i, _v) in res.iter().enumerate() {
+                if mask[i] == 1 {
+                    candidates.push(i);
+                }
+            }
+            max_index = *candidates.choose(&mut thread_rng()).unwrap();
+        }
+
+        Ok((
+            get_after_half_candidate_by_index(max_index, &candidates),
+            max_index,
+        ))
+    }
+
+    fn select_tymok(
+        &self,
+        state: &state::HandNotResolved,
+    ) -> Result<(bool, usize), Box<dyn Error>> {
+        let mask = tymok_mask();
+        let state_vec = state_to_feature(&Phase::Moved(state.clone()));
+
+        let res = self
+            .acting_q(vec![&state_vec[..]], vec![&mask[..]])
+            .pop()
+            .unwrap();
+
+        let mut max_value = f32::NEG_INFINITY;
+        let mut max_index = 0;
+
+        if self.noisy() || rand::random::<f32>() < 0.98f32 {
+            for (i, v) in res.iter().enumerate() {
+                if mask[i] == 1 && max_value < *v {
+                    max_index = i;
+                    max_value = *v;
+                }
+            }
+        } else {
+            let mut candidates = Vec::new();
+            for (i, _v) in res.iter().enumerate() {
+                if mask[i] == 1 {
+                    candidates.push(i);
+                }
+            }
+            max_index = *candidates.choose(&mut thread_rng()).unwrap();
+        }
+
+        Ok((get_tymok_candidate_by_index(max_index), max_index))
+    }
+
+    fn select_action(&self, state: &Phase) -> (Action, usize) {
+        match state {
+            Phase::Start(state) => {
+                let (mov, index) = self.select_move(state).unwrap();
+                (Action::Pure(mov), index)
+            }
+            Phase::AfterCiurl(state) => {
+                let (mov, index) = self.select_stepped(state).unwrap();
+                (Action::AfterHalf(mov), index)
+            }
+            Phase::Moved(state) => {
+                let (mov, index) = self.select_tymok(state).unwrap();
+                (Action::IsTymok(mov), index)
+            }
+        }
+    }
+
+    fn parallel_select_action (&self, states: &Vec<Phase> ) -> Vec< (Action, usize) > {
+        self.parallel_select_action_with_heads(states, None)
+    }
+
+    // with `heads`, row i is played greedily by the head `heads[i]` of the ensemble
+    fn parallel_select_action_with_heads(&self, states: &Vec<Phase>, heads: Option<&[usize]>) -> Vec<(Action, usize)> {
+        enum Candidates {
+            Start(Vec<PureMove>,Vec<PureMove>),
+            AfterCiurl(Vec<AfterHalfAcceptance>),
+            Moved
+        }
+
+        let mut vecs = Vec::new();
+        let mut masks = Vec::new();
+        let mut candidates_vec = Vec::new();
+
+        for state in states.iter() {
+            vecs.push( state_to_feature(state) );
+            masks.push(
+                match state {
+                Phase::Start(state) => {
+                    let (hop1zuo1_candidates, candidates) = state.get_candidates(Config::cerke_online_alpha());
+                    let res = candidates_to_mask(&hop1zuo1_candidates, &candidates);
+                    candidates_vec.push(Candidates::Start(hop1zuo1_candidates, candidates));
+                    res
+                },
+                Phase::AfterCiurl(state) => {
+                    let candidates = state.get_candidates(Config::cerke_online_alpha());
+                    let res = afterhalf_candidates_to_mask(&candidates);
+                    candidates_vec.push(Candidates::AfterCiurl(candidates));
+                    res
+                },
+                Phase::Moved(_) => {
+                    candidates_vec.push(Candidates::Moved);
+                    tymok_mask()
+                }
+            })
+        }
+        let batch = vecs.iter().map(|x| x.as_slice()).collect::<Vec<&[f32]>>();
+        let legal = masks.iter().map(|x| &x[..]).collect::<Vec<&[i8]>>();
+        let raw_res = match heads {
+            Some(heads) => self.acting_q_of_heads(batch, legal, heads),
+            None => self.acting_q(batch, legal),
+        };
+        
+        let mut result = Vec::new();
+        for (i, (res, candidates)) in raw_res.into_iter().zip(candidates_vec).enumerate() {
+            let mask = masks[i];
+
+            let mut max_value = f32::NEG_INFINITY;
+            let mut max_index = 0;
+
+            if let Some(temperature) = self.temperature() {
+                max_index = sample_softmax(&res, &mask, temperature);
+            } else if heads.is_some() || self.noisy() || rand::random::<f32>() < 0.98f32 {
+                for (i, v) in res.iter().enumerate() {
+                    if mask[i] == 1 && max_value < *v {
+                        max_index = i;
+                        max_value = *v;
+                    }
+                }
+            } else {
+                let mut candidates = Vec::new();
+                for (i, _v) in res.iter().enumerate() {
+                    if mask[i] == 1 {
+                        candidates.push(i);
+                    }
+                }
+                max_index = *candidates.choose(&mut thread_rng()).unwrap();
+            }
+            
+            result.push( match candidates {
+                Candidates::Start(hop1zuo1_candidates, candidates) => { 
+                    (
+                        Action::Pure(get_candidate_by_index(max_index, &hop1zuo1_candidates, &candidates)),
+                        max_index,
+                    )
+                },
+                Candidates::AfterCiurl(candidates) => {
+                    (
+                        Action::AfterHalf(get_after_half_candidate_by_index(max_index, &candidates)),
+                        max_index,
+                    )
+                },
+                Candidates::Moved => {
+                    (
+                        Action::IsTymok(get_tymok_candidate_by_index(max_index)),
+                        max_index
+                    )
+                },
+            })
+        } 
+
+        result
+    }
+
+    fn select_para (&self, environments: Vec<CerkeEnv>) -> Vec<(Action, usize)> {
+        let states: Vec<Phase> = environments.iter().map(|environment| environment.observe()).collect();
+        self.parallel_select_action(&states)
+    }
+}
+
+// a legal action drawn with probability proportional to `exp(q / temperature)`
+fn sample_softmax(q: &[f32], mask: &[i8], temperature: f32) -> usize {
+    let max = q
+        .iter()
+        .zip(mask)
+        .filter(|(_, m)| **m == 1)
+        .map(|(x, _)| *x)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let weights: Vec<f32> = q
+        .iter()
+        .zip(mask)
+        .map(|(x, m)| if *m == 1 { ((x - max) / temperature).exp() } else { 0f32 })
+        .collect();
+    rand::distributions::WeightedIndex::new(weights)
+        .unwrap()
+        .sample(&mut thread_rng())
+}
@@ -0,0 +1,526 @@
+use std::{collections::VecDeque, num::NonZeroU64};
+
+use anyhow::{ensure, Result};
+use cetkaik_full_state_transition::state::Phase;
+use chrono::Utc;
+use rand::{thread_rng, Rng};
+use tch::{nn, nn::VarStore, Device, Kind, Tensor};
+
+use super::{
+    checkpoint::{self, CheckpointMeta, Model},
+    environment::ParallelCerke,
+    layers::{policy_value_module, PolicyValueModule},
+    metrics::PolicyValueMetrics,
+    network::NetworkSpec,
+    optimizer::{Optimizer, OptimizerConfig},
+    policy::Policy,
+    replay::EncodedPhase,
+};
+use crate::learn::{
+    episode::Episode,
+    state_to_feature::{ACTION_SIZE, STATE_SIZE},
+};
+
+// what the policy target of a position is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyTarget {
+    // search visit distributions, learnt by cross-entropy
+    Visits,
+    // the moves played, each weighted by how much better its outcome was than the value head
+    // predicted: a policy gradient with the value head as baseline
+    Advantage,
+}
+
+// the AlphaZero-style counterpart of `Brain`: a network giving a policy over the legal
+// actions and the value of the position for the player to move
+pub trait PolicyValueBrain {
+    // `states` is row-major with one row per position and `legal` holds the matching
+    // legal-action masks; `policies` holds one target distribution over the actions per row,
+    // of the kind `targets` says for that row, and `values` the outcome the player to move
+    // went on to get
+    fn train(
+        &mut self,
+        states: &[f32],
+        legal: &[i8],
+        policies: &[f32],
+        values: &[f32],
+        targets: &[PolicyTarget],
+    ) -> Result<PolicyValueMetrics>;
+    // probabilities of every action, zero for the illegal ones, and the value of each row
+    fn predict(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<(Vec<Vec<f32>>, Vec<f32>)>;
+    // writes a checkpoint directory recording the train step `iteration`
+    fn save(&self, name: &String, iteration: i64) -> Result<()>;
+    // restores a checkpoint written by `save` and returns its train step
+    fn load(&mut self, name: &String) -> Result<i64>;
+}
+
+pub struct PolicyValueNet {
+    spec: NetworkSpec,
+    device: Device,
+    vs: VarStore,
+    net: Box<dyn PolicyValueModule>,
+    opt: Optimizer,
+}
+
+impl PolicyValueNet {
+    pub fn new() -> Self {
+        Self::with_spec(NetworkSpec::default())
+    }
+
+    pub fn with_spec(spec: NetworkSpec) -> Self {
+        Self::with_config(spec, OptimizerConfig::default())
+    }
+
+    pub fn with_config(spec: NetworkSpec, optimizer: OptimizerConfig) -> Self {
+        let device = Device::cuda_if_available();
+        let vs = nn::VarStore::new(device);
+        let net = policy_value_module(&vs.root(), &spec);
+        let opt = Optimizer::new(&vs, optimizer);
+        Self {
+            spec,
+            device,
+            vs,
+            net,
+            opt,
+        }
+    }
+
+    pub fn spec(&self) -> &NetworkSpec {
+        &self.spec
+    }
+
+    pub fn optimizer(&self) -> &OptimizerConfig {
+        self.opt.config()
+    }
+
+    // log-probabilities of every action, far below zero for the illegal ones
+    pub fn log_policy(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<Vec<Vec<f32>>> {
+        let input_tensor = self.input_tensor(batch);
+        let legal_tensor = self.legal_tensor(&legal.concat());
+        let (log_policy, _value) = tch::no_grad(|| self.net.forward_pv(&input_tensor, &legal_tensor, false));
+        Ok(log_policy.into())
+    }
+
+    fn input_tensor(&self, batch: Vec<&[f32]>) -> Tensor {
+        Tensor::of_slice(&batch.concat())
+            .reshape(&[batch.len() as i64, STATE_SIZE as i64])
+            .to(self.device)
+    }
+
+    fn legal_tensor(&self, legal: &[i8]) -> Tensor {
+        Tensor::of_slice(legal)
+            .reshape(&[-1, ACTION_SIZE as i64])
+            .to_kind(Kind::Float)
+            .to(self.device)
+    }
+}
+
+impl PolicyValueBrain for PolicyValueNet {
+    fn train(
+        &mut self,
+        states: &[f32],
+        legal: &[i8],
+        policies: &[f32],
+        values: &[f32],
+        targets: &[PolicyTarget],
+    ) -> Result<PolicyValueMetrics> {
+        ensure!(targets.len() == values.len(), "one policy target kind per row is needed");
+        let batch_size = values.len() as i64;
+        let input_tensor = Tensor::of_slice(states)
+            .reshape(&[batch_size, STATE_SIZE as i64])
+            .to(self.device);
+        let legal_tensor = self.legal_tensor(legal);
+        let policy_tensor = Tensor::of_slice(policies)
+            .reshape(&[batch_size, ACTION_SIZE as i64])
+            .to(self.device);
+        let value_tensor = Tensor::of_slice(values).to(self.device);
+
+        let (log_policy, value) = self.net.forward_pv(&input_tensor, &legal_tensor, true);
+        let cross_entropy = -(&policy_tensor * &log_policy).sum_dim_intlist(&[1], false, Kind::Float);
+        // advantage rows are weighted by how much better their outcome was than predicted; the
+        // baseline only lowers the variance, so no gradient flows into it from here
+        let advantage_rows: Vec<f32> = targets
+            .iter()
+            .map(|x| (*x == PolicyTarget::Advantage) as i32 as f32)
+            .collect();
+        let advantage_rows = Tensor::of_slice(&advantage_rows).to(self.device);
+        let weight = &advantage_rows * (&value_tensor - value.detach()) + (1f64 - &advantage_rows);
+        let policy_loss = (cross_entropy * weight).mean(Kind::Float);
+        let value_loss = (&value - &value_tensor).square().mean(Kind::Float);
+        let loss = &policy_loss + &value_loss;
+        let loss = match self.opt.l1_penalty() {
+            Some(penalty) => loss + penalty,
+            None => loss,
+        };
+
+        // illegal actions have no probability, and their masked log-probability must not
+        // turn the entropy into 0 * -1e9
+        let entropy = tch::no_grad(|| {
+            -(log_policy.exp() * &log_policy * &legal_tensor)
+                .sum_dim_intlist(&[1], false, Kind::Float)
+                .mean(Kind::Float)
+        });
+
+        let lr = self.opt.lr();
+        let grad_norm = self.opt.backward_step(&loss);
+        Ok(PolicyValueMetrics {
+            loss: f64::from(&loss),
+            policy_loss: f64::from(&policy_loss),
+            value_loss: f64::from(&value_loss),
+            policy_entropy: f64::from(&entropy),
+            mean_value: f64::from(value.detach().mean(Kind::Float)),
+            grad_norm,
+            lr,
+            batch_size: values.len(),
+        })
+    }
+
+    fn predict(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<(Vec<Vec<f32>>, Vec<f32>)> {
+        let input_tensor = self.input_tensor(batch);
+        let legal_tensor = self.legal_tensor(&legal.concat());
+        let (log_policy, value) = tch::no_grad(|| self.net.forward_pv(&input_tensor, &legal_tensor, false));
+        Ok(((log_policy.exp() * legal_tensor).into(), value.into()))
+    }
+
+    fn save(&self, name: &String, iteration: i64) -> Result<()> {
+        let meta = CheckpointMeta {
+            model: Model::PolicyValue,
+            iteration,
+            network: self.spec.clone(),
+            optimizer: self.opt.to_json(),
+        };
+        meta.save(name)?;
+        self.vs.save(checkpoint::file(name, checkpoint::LEARN))?;
+        self.opt.save_state(&checkpoint::file(name, checkpoint::OPTIMIZER))
+    }
+
+    fn load(&mut self, name: &String) -> Result<i64> {
+        let meta = CheckpointMeta::load(name)?;
+        ensure!(
+            meta.model == Model::PolicyValue,
+            "{} holds a {:?} network, not a policy/value network",
+            name,
+            meta.model
+        );
+        if meta.network != self.spec {
+            *self = Self::with_config(meta.network.clone(), self.opt.config().clone());
+        }
+        self.vs.load(checkpoint::file(name, checkpoint::LEARN))?;
+        self.opt
+            .load(&meta.optimizer, &checkpoint::file(name, checkpoint::OPTIMIZER))?;
+        Ok(meta.iteration)
+    }
+}
+
+// what the network learns from one position of self-play
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfPlayTarget {
+    pub state: EncodedPhase,
+    // probability of each action, by action index
+    pub policy: Vec<(u16, f32)>,
+    // how `policy` is learnt
+    pub target: PolicyTarget,
+    // outcome for the player to move
+    pub value: f32,
+}
+
+impl SelfPlayTarget {
+    // `visits` are search visit counts by action index, normalised into the policy. nothing in
+    // this crate searches yet: this is the entry point for an external one
+    pub fn from_visits(state: EncodedPhase, visits: &[(u16, u32)], value: f32) -> Self {
+        let total: u32 = visits.iter().map(|(_, n)| n).sum();
+        let policy = visits
+            .iter()
+            .filter(|(_, n)| *n > 0)
+            .map(|(action, n)| (*action, *n as f32 / total as f32))
+            .collect();
+        Self {
+            state,
+            policy,
+            target: PolicyTarget::Visits,
+            value,
+        }
+    }
+
+    fn dense_policy(&self, out: &mut [f32]) {
+        for (action, p) in self.policy.iter() {
+            out[*action as usize] = *p;
+        }
+    }
+}
+
+// which outcome positions are valued by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    // everything the player earned until the end of the game
+    Game,
+    // what the player earned until the end of the hand (season) the position belongs to
+    Hand,
+}
+
+// one target per decision of `episode`, with the move played as the policy target, learnt as
+// `PolicyTarget::Advantage`
+pub fn targets_from_episode(episode: &Episode<Phase, usize>, outcome: Outcome) -> Vec<SelfPlayTarget> {
+    let mut targets = Vec::with_capacity(episode.len());
+    for side in 0..2 {
+        let steps = &episode.sides[side];
+        let values = match outcome {
+            Outcome::Game => episode.returns(side, 1.0),
+            Outcome::Hand => {
+                let mut values = vec![0f32; steps.len()];
+                let mut g = 0f32;
+                for (i, step) in steps.iter().enumerate().rev() {
+                    let same_hand = steps
+                        .get(i + 1)
+                        .map_or(false, |next| next.state.get_season() == step.state.get_season());
+                    g = step.reward + if same_hand { g } else { 0f32 };
+                    values[i] = g;
+                }
+                values
+            }
+        };
+        for (step, value) in steps.iter().zip(values) {
+            targets.push(SelfPlayTarget {
+                state: EncodedPhase::encode(&step.state),
+                policy: vec![(step.action as u16, 1f32)],
+                target: PolicyTarget::Advantage,
+                value,
+            });
+        }
+    }
+    targets
+}
+
+#[derive(Clone, Debug)]
+pub struct PolicyValueConfig {
+    pub buffer_capacity: usize,
+    pub batch_size: usize,
+    // train steps between checkpoints
+    pub checkpoint_every: NonZeroU64,
+    pub network: NetworkSpec,
+    pub optimizer: OptimizerConfig,
+    pub outcome: Outcome,
+    // of the softmax self-play samples moves from
+    pub temperature: f32,
+}
+
+impl Default for PolicyValueConfig {
+    fn default() -> Self {
+        Self {
+            buffer_capacity: 100_000,
+            batch_size: 1000,
+            checkpoint_every: NonZeroU64::new(1000).unwrap(),
+            network: NetworkSpec::default(),
+            optimizer: OptimizerConfig::default(),
+            outcome: Outcome::Game,
+            temperature: 1.0,
+        }
+    }
+}
+
+pub struct PolicyValueAgent {
+    config: PolicyValueConfig,
+    net: PolicyValueNet,
+    buffer: VecDeque<SelfPlayTarget>,
+    it: i64,
+    name: String,
+    metrics: Vec<PolicyValueMetrics>,
+}
+
+impl PolicyValueAgent {
+    pub fn new() -> Self {
+        Self::with_config(PolicyValueConfig::default())
+    }
+
+    pub fn with_config(config: PolicyValueConfig) -> Self {
+        let net = PolicyValueNet::with_config(config.network.clone(), config.optimizer.clone());
+        Self {
+            buffer: VecDeque::with_capacity(config.buffer_capacity),
+            config,
+            net,
+            it: 0,
+            name: Utc::now().format("%Y%m%dT%H%M%S").to_string(),
+            metrics: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &PolicyValueConfig {
+        &self.config
+    }
+
+    pub fn net(&self) -> &PolicyValueNet {
+        &self.net
+    }
+
+    pub fn put_targets(&mut self, targets: Vec<SelfPlayTarget>) {
+        for target in targets {
+            if self.buffer.len() >= self.config.buffer_capacity {
+                self.buffer.pop_front();
+            }
+            self.buffer.push_back(target);
+        }
+    }
+
+    pub fn put_episode(&mut self, episode: &Episode<Phase, usize>) {
+        self.put_targets(targets_from_episode(episode, self.config.outcome));
+    }
+
+    // one train step on targets drawn uniformly from the buffer
+    pub fn train(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut rng = thread_rng();
+        let batch_size = self.config.batch_size;
+        let mut states = Vec::with_capacity(batch_size * STATE_SIZE);
+        let mut legal = Vec::with_capacity(batch_size * ACTION_SIZE);
+        let mut policies = vec![0f32; batch_size * ACTION_SIZE];
+        let mut values = Vec::with_capacity(batch_size);
+        let mut targets = Vec::with_capacity(batch_size);
+        for i in 0..batch_size {
+            let target = &self.buffer[rng.gen_range(0..self.buffer.len())];
+            states.extend_from_slice(&target.state.feature());
+            legal.extend_from_slice(&target.state.mask());
+            target.dense_policy(&mut policies[i * ACTION_SIZE..(i + 1) * ACTION_SIZE]);
+            values.push(target.value);
+            targets.push(target.target);
+        }
+
+        let metrics = self
+            .net
+            .train(&states, &legal, &policies, &values, &targets)
+            .expect("Train Failed");
+        self.metrics.push(metrics);
+
+        self.it += 1;
+        if self.it as u64 % self.config.checkpoint_every.get() == 0 {
+            let path = format!("./result/{}", self.name);
+            self.net.save(&path, self.it).expect("cannot save the checkpoint");
+        }
+    }
+
+    // plays a round of self-play games, learns from the finished ones and takes a train step
+    pub fn iteration(&mut self, env: &mut ParallelCerke) {
+        let episodes = env.self_play(&*self);
+        for episode in episodes.iter() {
+            self.put_episode(episode);
+        }
+        self.train();
+    }
+
+    // what each train step saw so far
+    pub fn metrics(&self) -> &[PolicyValueMetrics] {
+        &self.metrics
+    }
+
+    pub fn last_metrics(&self) -> Option<&PolicyValueMetrics> {
+        self.metrics.last()
+    }
+}
+
+// the log-probabilities stand in for Q-values, so the softmax of `temperature` samples
+// moves from the policy itself
+impl Policy for PolicyValueAgent {
+    fn acting_q(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<f32>> {
+        self.net.log_policy(batch, legal).unwrap()
+    }
+
+    fn noisy(&self) -> bool {
+        false
+    }
+
+    fn head_q(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<Vec<f32>>> {
+        self.acting_q(batch, legal).into_iter().map(|q| vec![q]).collect()
+    }
+
+    fn temperature(&self) -> Option<f32> {
+        Some(self.config.temperature)
+    }
+}
+
+#[test]
+fn test_policy_value_train() {
+    let spec = NetworkSpec {
+        widths: vec![32],
+        ..NetworkSpec::default()
+    };
+    let mut net = PolicyValueNet::with_spec(spec);
+
+    let state: Vec<f32> = (0..STATE_SIZE).map(|i| (i % 5 == 0) as i32 as f32).collect();
+    let legal: Vec<i8> = (0..ACTION_SIZE).map(|i| (i < 4) as i8).collect();
+    let mut policy = vec![0f32; ACTION_SIZE];
+    policy[2] = 1.0;
+
+    let first = net.train(&state, &legal, &policy, &[1.0], &[PolicyTarget::Visits]).unwrap();
+    let mut last = first.clone();
+    for _ in 0..50 {
+        last = net.train(&state, &legal, &policy, &[1.0], &[PolicyTarget::Visits]).unwrap();
+    }
+    assert!(last.loss < first.loss);
+    assert_eq!(last.batch_size, 1);
+
+    let (policies, values) = net.predict(vec![&state[..]], vec![&legal[..]]).unwrap();
+    let sum: f32 = policies[0].iter().sum();
+    assert!((sum - 1.0).abs() < 1e-4);
+    assert!(policies[0][4..].iter().all(|p| *p == 0.0));
+    assert!(policies[0][2] > 0.5);
+    assert_eq!(values.len(), 1);
+}
+
+#[test]
+fn test_advantage_policy_gradient() {
+    let spec = NetworkSpec {
+        widths: vec![32],
+        ..NetworkSpec::default()
+    };
+    let state: Vec<f32> = (0..STATE_SIZE).map(|i| (i % 5 == 0) as i32 as f32).collect();
+    let legal: Vec<i8> = (0..ACTION_SIZE).map(|i| (i < 4) as i8).collect();
+    let mut policy = vec![0f32; ACTION_SIZE];
+    policy[2] = 1.0;
+
+    // the value head starts near 0, so an outcome of 1 makes the played move better than
+    // expected and one of -1 makes it worse
+    let played = |outcome: f32| {
+        let mut net = PolicyValueNet::with_spec(spec.clone());
+        let before = net.predict(vec![&state[..]], vec![&legal[..]]).unwrap().0[0][2];
+        for _ in 0..5 {
+            net.train(&state, &legal, &policy, &[outcome], &[PolicyTarget::Advantage]).unwrap();
+        }
+        net.predict(vec![&state[..]], vec![&legal[..]]).unwrap().0[0][2] - before
+    };
+    assert!(played(1.0) > 0.0);
+    assert!(played(-1.0) < 0.0);
+}
+
+#[test]
+fn test_targets_from_episode() {
+    use crate::learn::episode::Step;
+    use cetkaik_full_state_transition::Season;
+
+    let (e, _) = cetkaik_full_state_transition::initial_state().choose();
+    let mut next = e.clone();
+    next.season = Season::Xo1;
+    let (first, second) = (Phase::Start(e), Phase::Start(next));
+    let step = |state: &Phase, action: usize, reward: f32| Step {
+        state: state.clone(),
+        action,
+        reward,
+    };
+
+    // the last move of the first side belongs to the next hand
+    let mut episode = Episode::new();
+    episode.sides[0] = vec![step(&first, 1, 1.0), step(&first, 2, 2.0), step(&second, 3, 4.0)];
+    episode.sides[1] = vec![step(&first, 5, -3.0)];
+
+    let values = |outcome| -> Vec<f32> {
+        targets_from_episode(&episode, outcome).iter().map(|x| x.value).collect()
+    };
+    assert_eq!(values(Outcome::Game), vec![7.0, 6.0, 4.0, -3.0]);
+    assert_eq!(values(Outcome::Hand), vec![3.0, 2.0, 4.0, -3.0]);
+
+    let targets = targets_from_episode(&episode, Outcome::Game);
+    let policies: Vec<_> = targets.iter().map(|x| x.policy.clone()).collect();
+    assert_eq!(policies, vec![vec![(1, 1.0)], vec![(2, 1.0)], vec![(3, 1.0)], vec![(5, 1.0)]]);
+    assert_eq!(targets[2].state, EncodedPhase::encode(&second));
+    assert!(targets.iter().all(|x| x.target == PolicyTarget::Advantage));
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter, Read, Write},
+};
+
+use anyhow::{bail, ensure, Result};
+use cetkaik_full_state_transition::state::Phase;
+
+use crate::learn::{
+    memory::{Experience, Features, Memory},
+    state_to_feature::{
+        packed_is_valid, packed_to_feature, phase_to_mask, state_to_packed, ACTION_SIZE,
+        FEATURE_VERSION, PACKED_SIZE, STATE_SIZE,
+    },
+};
+
+const MAGIC: &[u8; 8] = b"CRKREPLY";
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhaseKind {
+    Start,
+    AfterCiurl,
+    Moved,
+}
+
+impl PhaseKind {
+    fn from_u8(x: u8) -> Result<Self> {
+        Ok(match x {
+            0 => PhaseKind::Start,
+            1 => PhaseKind::AfterCiurl,
+            2 => PhaseKind::Moved,
+            _ => bail!("unknown phase kind {}", x),
+        })
+    }
+}
+
+const LEGAL_WORDS: usize = (ACTION_SIZE + 63) / 64;
+
+// a `Phase` reduced to what training needs: its one-hot feature bit-packed as
+// piece indices and hand counts, and its legal actions as a bitset. both are stored
+// inline, so keeping a phase in the replay buffer allocates nothing of its own
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedPhase {
+    kind: PhaseKind,
+    packed: [u8; PACKED_SIZE],
+    legal: [u64; LEGAL_WORDS],
+}
+
+impl EncodedPhase {
+    pub fn encode(state: &Phase) -> Self {
+        let kind = match state {
+            Phase::Start(_) => PhaseKind::Start,
+            Phase::AfterCiurl(_) => PhaseKind::AfterCiurl,
+            Phase::Moved(_) => PhaseKind::Moved,
+        };
+        let packed = state_to_packed(state);
+        let mut legal = [0u64; LEGAL_WORDS];
+        for (i, x) in phase_to_mask(state).iter().enumerate() {
+            if *x != 0 {
+                legal[i / 64] |= 1 << (i % 64);
+            }
+        }
+        Self {
+            kind,
+            packed,
+            legal,
+        }
+    }
+
+    pub fn kind(&self) -> PhaseKind {
+        self.kind
+    }
+
+    // hash of the position, to find the experiences of a state without keeping the state
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (self.kind as u8).hash(&mut hasher);
+        self.packed.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn feature(&self) -> [f32; STATE_SIZE] {
+        let mut res = [0f32; STATE_SIZE];
+        packed_to_feature(&self.packed, &mut res);
+        res
+    }
+
+    pub fn mask(&self) -> [i8; ACTION_SIZE] {
+        let mut mask = [0; ACTION_SIZE];
+        for i in self.legal_actions() {
+            mask[i] = 1;
+        }
+        mask
+    }
+
+    // in increasing order
+    pub fn legal_actions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.legal.iter().enumerate().flat_map(|(word, bits)| {
+            (0..64)
+                .filter(move |bit| (bits >> bit) & 1 == 1)
+                .map(move |bit| word * 64 + bit)
+        })
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(&[self.kind as u8])?;
+        w.write_all(&self.packed)?;
+        let legal: Vec<u16> = self.legal_actions().map(|i| i as u16).collect();
+        write_indices(w, &legal)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> Result<Self> {
+        let kind = PhaseKind::from_u8(read_u8(r)?)?;
+        let mut packed = [0u8; PACKED_SIZE];
+        r.read_exact(&mut packed)?;
+        ensure!(packed_is_valid(&packed), "corrupted packed state");
+        let mut legal = [0u64; LEGAL_WORDS];
+        for i in read_indices(r, ACTION_SIZE)? {
+            legal[i as usize / 64] |= 1 << (i % 64);
+        }
+        Ok(Self {
+            kind,
+            packed,
+            legal,
+        })
+    }
+}
+
+impl Features for EncodedPhase {
+    const WIDTH: usize = STATE_SIZE;
+
+    fn write_features(&self, out: &mut [f32]) {
+        packed_to_feature(&self.packed, out);
+    }
+}
+
+fn write_indices<W: Write>(w: &mut W, indices: &[u16]) -> Result<()> {
+    w.write_all(&(indices.len() as u16).to_le_bytes())?;
+    for i in indices.iter() {
+        w.write_all(&i.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_indices<R: Read>(r: &mut R, bound: usize) -> Result<Vec<u16>> {
+    let len = read_u16(r)? as usize;
+    let mut indices = Vec::with_capacity(len);
+    for _ in 0..len {
+        let i = read_u16(r)?;
+        ensure!((i as usize) < bound, "index {} out of range {}", i, bound);
+        indices.push(i);
+    }
+    Ok(indices)
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16<R: Read>(r: &mut R) -> Result<u16> {
+    let mut buf = [0u8; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_f32<R: Read>(r: &mut R) -> Result<f32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+pub fn save_memory(memory: &Memory<EncodedPhase, usize>, path: &str) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    w.write_all(&(STATE_SIZE as u32).to_le_bytes())?;
+    w.write_all(&(ACTION_SIZE as u32).to_le_bytes())?;
+    w.write_all(&FEATURE_VERSION.to_le_bytes())?;
+    w.write_all(&(memory.len() as u64).to_le_bytes())?;
+
+    for experience in memory.iter() {
+        w.write_all(&(experience.action as u32).to_le_bytes())?;
+        w.write_all(&experience.value.to_le_bytes())?;
+        w.write_all(&[experience.done as u8])?;
+        w.write_all(&experience.steps.to_le_bytes())?;
+        experience.current_state.write_to(&mut w)?;
+        experience.next_state.write_to(&mut w)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+// appends the experiences stored at `path` to `memory`, oldest first
+pub fn load_memory(memory: &mut Memory<EncodedPhase, usize>, path: &str) -> Result<()> {
+    let mut r = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("{} is not a replay buffer file", path);
+    }
+    let version = read_u32(&mut r)?;
+    ensure!(
+        version == FORMAT_VERSION,
+        "replay buffer format version {} is not supported (expected {})",
+        version,
+        FORMAT_VERSION
+    );
+    let (state_size, action_size) = (read_u32(&mut r)? as usize, read_u32(&mut r)? as usize);
+    let feature_version = read_u32(&mut r)?;
+    ensure!(
+        state_size == STATE_SIZE && action_size == ACTION_SIZE && feature_version == FEATURE_VERSION,
+        "replay buffer was encoded with STATE_SIZE={} ACTION_SIZE={} FEATURE_VERSION={} \
+         but this build uses STATE_SIZE={} ACTION_SIZE={} FEATURE_VERSION={}",
+        state_size,
+        action_size,
+        feature_version,
+        STATE_SIZE,
+        ACTION_SIZE,
+        FEATURE_VERSION
+    );
+
+    let len = read_u64(&mut r)?;
+    for _ in 0..len {
+        let action = read_u32(&mut r)? as usize;
+        ensure!(action < ACTION_SIZE, "action {} out of range", action);
+        let value = read_f32(&mut r)?;
+        let done = read_u8(&mut r)? != 0;
+        let steps = read_u32(&mut r)?;
+        let current_state = EncodedPhase::read_from(&mut r)?;
+        let next_state = EncodedPhase::read_from(&mut r)?;
+        memory.put(Experience {
+            current_state,
+            action,
+            next_state,
+            value,
+            done,
+            steps,
+        });
+    }
+    Ok(())
+}
+
+#[test]
+fn test_save_load_memory() {
+    let (e, _) = cetkaik_full_state_transition::initial_state().choose();
+    let state = EncodedPhase::encode(&Phase::Start(e));
+
+    let mut memory = Memory::new();
+    for i in 0..3 {
+        memory.put(Experience {
+            current_state: state.clone(),
+            action: i,
+            next_state: state.clone(),
+            value: i as f32,
+            done: i == 2,
+            steps: i as u32 + 1,
+        });
+    }
+
+    let file = crate::learn::temp_file::TempFile::new("replay.bin");
+    let path = file.path();
+    save_memory(&memory, path).unwrap();
+
+    let mut loaded = Memory::new();
+    load_memory(&mut loaded, path).unwrap();
+    assert_eq!(loaded.len(), 3);
+    for (x, y) in memory.iter().zip(loaded.iter()) {
+        assert_eq!(x.action, y.action);
+        assert_eq!(x.value, y.value);
+        assert_eq!(x.done, y.done);
+        assert_eq!(x.steps, y.steps);
+        assert_eq!(x.current_state, y.current_state);
+        assert_eq!(x.next_state, y.next_state);
+    }
+
+    // a buffer written under another feature layout is refused
+    let mut bytes = std::fs::read(path).unwrap();
+    bytes[20..24].copy_from_slice(&(FEATURE_VERSION + 1).to_le_bytes());
+    std::fs::write(path, bytes).unwrap();
+    assert!(load_memory(&mut Memory::new(), path).is_err());
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use super::replay::{EncodedPhase, PhaseKind};
+use crate::learn::{
+    memory::Memory,
+    state_to_feature::{action_family, ActionFamily},
+};
+
+const FAMILIES: [ActionFamily; 4] = [
+    ActionFamily::BoardMove,
+    ActionFamily::Drop,
+    ActionFamily::AfterHalf,
+    ActionFamily::TymokTaxot,
+];
+const PHASES: [PhaseKind; 3] = [PhaseKind::Start, PhaseKind::AfterCiurl, PhaseKind::Moved];
+const REWARD_EDGES: [f32; 6] = [-10.0, -1.0, -0.01, 0.01, 1.0, 10.0];
+const TOP_ACTIONS: usize = 10;
+
+pub struct FamilyStats {
+    pub count: usize,
+    pub distinct: usize,
+    // most frequent action indices with their counts
+    pub top: Vec<(usize, usize)>,
+}
+
+pub struct Summary {
+    pub count: usize,
+    pub mean: f64,
+    pub std: f64,
+    pub min: f64,
+    pub max: f64,
+    pub p50: f64,
+    pub p90: f64,
+}
+
+impl Summary {
+    // non-finite values are left out
+    fn of(mut values: Vec<f64>) -> Self {
+        values.retain(|x| x.is_finite());
+        if values.is_empty() {
+            return Self {
+                count: 0,
+                mean: 0.0,
+                std: 0.0,
+                min: 0.0,
+                max: 0.0,
+                p50: 0.0,
+                p90: 0.0,
+            };
+        }
+        values.sort_by(f64::total_cmp);
+        let count = values.len();
+        let mean = values.iter().sum::<f64>() / count as f64;
+        let var = values.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / count as f64;
+        let quantile = |q: f64| values[((count - 1) as f64 * q).round() as usize];
+        Self {
+            count,
+            mean,
+            std: var.sqrt(),
+            min: values[0],
+            max: values[count - 1],
+            p50: quantile(0.5),
+            p90: quantile(0.9),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "count": self.count,
+            "mean": self.mean,
+            "std": self.std,
+            "min": self.min,
+            "max": self.max,
+            "p50": self.p50,
+            "p90": self.p90,
+        })
+    }
+}
+
+pub struct ReplayStats {
+    pub len: usize,
+    pub capacity: usize,
+    pub families: Vec<(ActionFamily, FamilyStats)>,
+    pub rewards: Summary,
+    // counts of rewards in the buckets split by `REWARD_EDGES`
+    pub reward_histogram: Vec<usize>,
+    pub terminal: usize,
+    pub phases: Vec<(PhaseKind, usize)>,
+    pub ages: Summary,
+}
+
+impl ReplayStats {
+    pub fn of(memory: &Memory<EncodedPhase, usize>) -> Self {
+        let mut action_counts: Vec<HashMap<usize, usize>> = vec![HashMap::new(); FAMILIES.len()];
+        let mut phase_counts = [0usize; 3];
+        let mut reward_histogram = vec![0usize; REWARD_EDGES.len() + 1];
+        let mut rewards = Vec::with_capacity(memory.len());
+        let mut ages = Vec::with_capacity(memory.len());
+        let mut terminal = 0;
+
+        for (age, experience) in memory.iter_with_age() {
+            let family = action_family(experience.action);
+            let family = FAMILIES.iter().position(|x| *x == family).unwrap();
+            *action_counts[family].entry(experience.action).or_insert(0) += 1;
+
+            let phase = PHASES
+                .iter()
+                .position(|x| *x == experience.current_state.kind())
+                .unwrap();
+            phase_counts[phase] += 1;
+
+            let bucket = REWARD_EDGES
+                .iter()
+                .position(|edge| experience.value < *edge)
+                .unwrap_or(REWARD_EDGES.len());
+            reward_histogram[bucket] += 1;
+            rewards.push(experience.value as f64);
+
+            ages.push(age as f64);
+            if experience.done {
+                terminal += 1;
+            }
+        }
+
+        let families = FAMILIES
+            .iter()
+            .zip(action_counts)
+            .map(|(family, counts)| {
+                let mut top: Vec<(usize, usize)> = counts.into_iter().collect();
+                let distinct = top.len();
+                let count = top.iter().map(|x| x.1).sum();
+                top.sort_by(|x, y| y.1.cmp(&x.1).then(x.0.cmp(&y.0)));
+                top.truncate(TOP_ACTIONS);
+                (
+                    *family,
+                    FamilyStats {
+                        count,
+                        distinct,
+                        top,
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            len: memory.len(),
+            capacity: memory.capacity(),
+            families,
+            rewards: Summary::of(rewards),
+            reward_histogram,
+            terminal,
+            phases: PHASES.iter().copied().zip(phase_counts).collect(),
+            ages: Summary::of(ages),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let families: serde_json::Map<String, Value> = self
+            .families
+            .iter()
+            .map(|(family, stats)| {
+                (
+                    format!("{:?}", family),
+                    json!({
+                        "count": stats.count,
+                        "distinct": stats.distinct,
+                        "top": stats.top.iter().map(|(action, count)| json!([action, count])).collect::<Vec<_>>(),
+                    }),
+                )
+            })
+            .collect();
+        let phases: serde_json::Map<String, Value> = self
+            .phases
+            .iter()
+            .map(|(phase, count)| (format!("{:?}", phase), json!(count)))
+            .collect();
+
+        json!({
+            "len": self.len,
+            "capacity": self.capacity,
+            "actions": families,
+            "rewards": self.rewards.to_json(),
+            "reward_histogram": {
+                "edges": REWARD_EDGES.to_vec(),
+                "counts": self.reward_histogram,
+            },
+            "terminal": self.terminal,
+            "phases": phases,
+            "ages": self.ages.to_json(),
+        })
+    }
+}
+
+#[test]
+fn test_replay_stats() {
+    use cetkaik_full_state_transition::state::Phase;
+
+    use crate::learn::{
+        memory::{Eviction, Experience, Sampling},
+        state_to_feature::ACTION_SIZE,
+    };
+
+    let (e, _) = cetkaik_full_state_transition::initial_state().choose();
+    let state = EncodedPhase::encode(&Phase::Start(e));
+    let drop = 81 * 81 + 3;
+    let mut memory = Memory::with_capacity(4, Eviction::Fifo, Sampling::Uniform);
+    // the first one is evicted by the last
+    for (action, value, done) in [
+        (5, -20.0, false),
+        (5, 0.0, false),
+        (5, 0.5, false),
+        (drop, 2.0, false),
+        (ACTION_SIZE - 1, 20.0, true),
+    ] {
+        memory.put(Experience {
+            current_state: state.clone(),
+            action,
+            next_state: state.clone(),
+            value,
+            done,
+            steps: 1,
+        });
+    }
+
+    let stats = ReplayStats::of(&memory);
+    assert_eq!((stats.len, stats.capacity, stats.terminal), (4, 4, 1));
+    assert_eq!(stats.reward_histogram, vec![0, 0, 0, 1, 1, 1, 1]);
+    assert_eq!(stats.rewards.count, 4);
+    assert_eq!(stats.rewards.mean, 5.625);
+    assert_eq!((stats.rewards.min, stats.rewards.max), (0.0, 20.0));
+    assert_eq!((stats.ages.min, stats.ages.max, stats.ages.mean), (0.0, 3.0, 1.5));
+    let (family, board) = &stats.families[0];
+    assert_eq!(*family, ActionFamily::BoardMove);
+    assert_eq!((board.count, board.distinct), (2, 1));
+    assert_eq!(board.top, vec![(5, 2)]);
+
+    let json = stats.to_json();
+    assert_eq!(json["len"], json!(4));
+    assert_eq!(json["terminal"], json!(1));
+    assert_eq!(json["actions"]["BoardMove"]["top"], json!([[5, 2]]));
+    assert_eq!(json["actions"]["Drop"]["count"], json!(1));
+    assert_eq!(json["actions"]["AfterHalf"]["count"], json!(0));
+    assert_eq!(json["actions"]["TymokTaxot"]["distinct"], json!(1));
+    assert_eq!(json["phases"]["Start"], json!(4));
+    assert_eq!(json["phases"]["Moved"], json!(0));
+    assert_eq!(json["reward_histogram"]["counts"], json!([0, 0, 0, 1, 1, 1, 1]));
+    assert_eq!(json["ages"]["max"], json!(3.0));
+}
+
+#[test]
+fn test_summary_of_non_finite() {
+    let summary = Summary::of(vec![3.0, f64::NAN, 1.0, f64::INFINITY]);
+    assert_eq!(summary.count, 2);
+    assert_eq!((summary.min, summary.max, summary.mean), (1.0, 3.0, 2.0));
+    assert_eq!(Summary::of(vec![f64::NAN]).count, 0);
+}
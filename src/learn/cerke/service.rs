@@ -0,0 +1,352 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use tch::Tensor;
+
+use super::{
+    brain::{Brain, QNet},
+    network::NetworkSpec,
+    policy::Policy,
+};
+use crate::learn::state_to_feature::{ACTION_SIZE, STATE_SIZE};
+
+#[derive(Clone, Copy, Debug)]
+pub struct ServiceConfig {
+    // rows evaluated by one forward at most
+    pub max_batch: usize,
+    // how long the first request of a batch waits for others to join it
+    pub max_latency: Duration,
+    // whether acting through the service as a `Policy` puts the noise of noisy layers on;
+    // off for a deployed agent so that its play is deterministic
+    pub explore: bool,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            max_batch: 512,
+            max_latency: Duration::from_millis(2),
+            explore: false,
+        }
+    }
+}
+
+// the rows of one caller, answered together
+struct Forward {
+    // row-major, one row per state
+    states: Vec<f32>,
+    legal: Vec<i8>,
+    explore: bool,
+    // answer with the values of every head, head after head
+    heads: bool,
+    reply: mpsc::Sender<Result<Vec<Vec<f32>>, String>>,
+}
+
+impl Forward {
+    fn rows(&self) -> usize {
+        self.legal.len() / ACTION_SIZE
+    }
+}
+
+enum Request {
+    Forward(Forward),
+    // new target-network weights, applied after the requests received before them
+    Update(HashMap<String, Tensor>, mpsc::Sender<Result<(), String>>),
+}
+
+#[derive(Default)]
+struct Counts {
+    forwards: AtomicU64,
+    rows: AtomicU64,
+}
+
+// a thread owning one QNet that merges the forwards of any number of callers into batches.
+// handles are cheap to clone and the thread stops once the last one is dropped
+pub struct InferenceService {
+    requests: Mutex<mpsc::Sender<Request>>,
+    spec: NetworkSpec,
+    explore: bool,
+    counts: Arc<Counts>,
+}
+
+impl Clone for InferenceService {
+    fn clone(&self) -> Self {
+        Self {
+            requests: Mutex::new(self.sender()),
+            spec: self.spec.clone(),
+            explore: self.explore,
+            counts: self.counts.clone(),
+        }
+    }
+}
+
+impl InferenceService {
+    // `make` builds the network on the service thread
+    pub fn spawn(make: impl FnOnce() -> Result<QNet> + Send + 'static, config: ServiceConfig) -> Result<Self> {
+        let (requests, receiver) = mpsc::channel();
+        let (ready, started) = mpsc::channel();
+        let counts = Arc::new(Counts::default());
+        let served = counts.clone();
+        thread::Builder::new()
+            .name("cerke-inference".to_string())
+            .spawn(move || {
+                let qnet = match make() {
+                    Ok(qnet) => qnet,
+                    Err(error) => {
+                        let _ = ready.send(Err(error));
+                        return;
+                    }
+                };
+                let _ = ready.send(Ok(qnet.spec().clone()));
+                serve(qnet, receiver, config, &served);
+            })?;
+        let spec = started
+            .recv()
+            .context("the inference thread stopped while building its network")??;
+        Ok(Self {
+            requests: Mutex::new(requests),
+            spec,
+            explore: config.explore,
+            counts,
+        })
+    }
+
+    pub fn from_checkpoint(path: &str, config: ServiceConfig) -> Result<Self> {
+        let path = path.to_string();
+        Self::spawn(
+            move || {
+                let mut qnet = QNet::new();
+                qnet.load(&path)?;
+                Ok(qnet)
+            },
+            config,
+        )
+    }
+
+    pub fn spec(&self) -> &NetworkSpec {
+        &self.spec
+    }
+
+    // forwards run so far and the rows they evaluated, over all the handles
+    pub fn forwards(&self) -> u64 {
+        self.counts.forwards.load(Ordering::Relaxed)
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.counts.rows.load(Ordering::Relaxed)
+    }
+
+    fn sender(&self) -> mpsc::Sender<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // `Brain::forward`, or `Brain::forward_explore` when `explore` is set, of the target network;
+    // the rows may share a batch with those of other callers
+    pub fn forward(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>, explore: bool) -> Result<Vec<Vec<f32>>> {
+        self.submit(batch, legal, explore, false)
+    }
+
+    // `Brain::forward_heads` of the target network
+    pub fn forward_heads(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Result<Vec<Vec<Vec<f32>>>> {
+        Ok(self
+            .submit(batch, legal, false, true)?
+            .into_iter()
+            .map(|row| row.chunks(ACTION_SIZE).map(|q| q.to_vec()).collect())
+            .collect())
+    }
+
+    fn submit(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>, explore: bool, heads: bool) -> Result<Vec<Vec<f32>>> {
+        if batch.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (reply, result) = mpsc::channel();
+        self.sender()
+            .send(Request::Forward(Forward {
+                states: batch.concat(),
+                legal: legal.concat(),
+                explore,
+                heads,
+                reply,
+            }))
+            .map_err(|_| anyhow!("the inference service has stopped"))?;
+        result
+            .recv()
+            .map_err(|_| anyhow!("the inference service has stopped"))?
+            .map_err(|error| anyhow!(error))
+    }
+
+    // replaces the weights of the served network with `QNet::target_variables` of a QNet
+    // of the same spec, once the requests sent before are answered
+    pub fn update(&self, vars: HashMap<String, Tensor>) -> Result<()> {
+        let (reply, result) = mpsc::channel();
+        self.sender()
+            .send(Request::Update(vars, reply))
+            .map_err(|_| anyhow!("the inference service has stopped"))?;
+        result
+            .recv()
+            .map_err(|_| anyhow!("the inference service has stopped"))?
+            .map_err(|error| anyhow!("cannot update the served network: {}", error))
+    }
+}
+
+impl Policy for InferenceService {
+    fn acting_q(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<f32>> {
+        self.forward(batch, legal, self.explore && self.noisy()).unwrap()
+    }
+
+    fn noisy(&self) -> bool {
+        self.spec.noisy.is_some()
+    }
+
+    fn head_q(&self, batch: Vec<&[f32]>, legal: Vec<&[i8]>) -> Vec<Vec<Vec<f32>>> {
+        self.forward_heads(batch, legal).unwrap()
+    }
+}
+
+fn serve(mut qnet: QNet, requests: mpsc::Receiver<Request>, config: ServiceConfig, counts: &Counts) {
+    let mut pending = Vec::new();
+    while let Ok(request) = requests.recv() {
+        let deadline = Instant::now() + config.max_latency;
+        let mut request = Some(request);
+        while let Some(received) = request.take() {
+            match received {
+                Request::Forward(forward) => pending.push(forward),
+                Request::Update(vars, reply) => {
+                    run(&qnet, &mut pending, counts);
+                    let _ = reply.send(qnet.set_target_variables(&vars).map_err(|error| format!("{:#}", error)));
+                }
+            }
+            if pending.iter().map(Forward::rows).sum::<usize>() >= config.max_batch {
+                break;
+            }
+            let now = Instant::now();
+            if now < deadline {
+                request = requests.recv_timeout(deadline - now).ok();
+            }
+        }
+        run(&qnet, &mut pending, counts);
+    }
+}
+
+// answers the pending requests, with one forward for each kind of request
+fn run(qnet: &QNet, pending: &mut Vec<Forward>, counts: &Counts) {
+    for (explore, heads) in [(false, false), (true, false), (false, true)] {
+        let (batch, rest): (Vec<Forward>, Vec<Forward>) = pending
+            .drain(..)
+            .partition(|x| x.explore == explore && x.heads == heads);
+        *pending = rest;
+        if batch.is_empty() {
+            continue;
+        }
+        counts.forwards.fetch_add(1, Ordering::Relaxed);
+        counts.rows.fetch_add(batch.iter().map(Forward::rows).sum::<usize>() as u64, Ordering::Relaxed);
+        let states = batch.iter().flat_map(|x| x.states.chunks(STATE_SIZE)).collect();
+        let legal = batch.iter().flat_map(|x| x.legal.chunks(ACTION_SIZE)).collect();
+        let result = if heads {
+            qnet.forward_heads(states, legal)
+                .map(|rows| rows.into_iter().map(|row| row.concat()).collect())
+        } else if explore {
+            qnet.forward_explore(states, legal)
+        } else {
+            qnet.forward(states, legal)
+        };
+        match result {
+            Ok(rows) => {
+                let mut rows = rows.into_iter();
+                for forward in batch.iter() {
+                    let _ = forward.reply.send(Ok(rows.by_ref().take(forward.rows()).collect()));
+                }
+            }
+            Err(error) => {
+                for forward in batch.iter() {
+                    let _ = forward.reply.send(Err(error.to_string()));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_inference_service() {
+    let qnet = QNet::new();
+    let vars = qnet.target_variables();
+    let service = InferenceService::spawn(
+        move || {
+            let mut qnet = QNet::new();
+            qnet.set_target_variables(&vars)?;
+            Ok(qnet)
+        },
+        ServiceConfig {
+            max_batch: 8,
+            max_latency: Duration::from_millis(20),
+            ..ServiceConfig::default()
+        },
+    )
+    .unwrap();
+
+    let states: Vec<Vec<f32>> = (0..4).map(|i| vec![(i % 2) as f32; STATE_SIZE]).collect();
+    let legal = vec![1i8; ACTION_SIZE];
+    let expected = qnet
+        .forward(states.iter().map(|x| &x[..]).collect(), vec![&legal[..]; 4])
+        .unwrap();
+
+    let handles: Vec<_> = states
+        .into_iter()
+        .map(|state| {
+            let (service, legal) = (service.clone(), legal.clone());
+            thread::spawn(move || service.forward(vec![&state[..]], vec![&legal[..]], false).unwrap())
+        })
+        .collect();
+    for (handle, expected) in handles.into_iter().zip(expected) {
+        let got = handle.join().unwrap().pop().unwrap();
+        for (x, y) in got.iter().zip(expected.iter()) {
+            assert!((x - y).abs() < 1e-4);
+        }
+    }
+}
+
+#[test]
+fn test_inference_service_batches_callers() {
+    let (callers, rows) = (4, 2);
+    // the first caller waits long enough for the others, which fill the batch
+    let service = InferenceService::spawn(
+        || Ok(QNet::with_spec(NetworkSpec::uniform(16, 1))),
+        ServiceConfig {
+            max_batch: callers * rows,
+            max_latency: Duration::from_secs(10),
+            ..ServiceConfig::default()
+        },
+    )
+    .unwrap();
+
+    let handles: Vec<_> = (0..callers)
+        .map(|i| {
+            let service = service.clone();
+            thread::spawn(move || {
+                let states: Vec<Vec<f32>> = (0..rows).map(|j| vec![((i + j) % 2) as f32; STATE_SIZE]).collect();
+                let legal = vec![1i8; ACTION_SIZE];
+                service.acting_q(states.iter().map(|x| &x[..]).collect(), vec![&legal[..]; rows])
+            })
+        })
+        .collect();
+    for handle in handles {
+        let q = handle.join().unwrap();
+        assert_eq!(q.len(), rows);
+        assert!(q.iter().all(|q| q.len() == ACTION_SIZE));
+    }
+    assert_eq!(service.rows(), (callers * rows) as u64);
+    assert_eq!(service.forwards(), 1);
+
+    // weights of another spec are refused, and the refusal reaches the caller
+    let other = QNet::with_spec(NetworkSpec::uniform(8, 1));
+    assert!(service.update(other.target_variables()).is_err());
+    assert!(service.update(QNet::with_spec(NetworkSpec::uniform(16, 1)).target_variables()).is_ok());
+}
@@ -0,0 +1,157 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tch::{CModule, Device, Kind, Tensor};
+
+use super::{
+    checkpoint::rule_config,
+    layers::QModule,
+    network::{NetworkSpec, HAND_FEATURES, PLANES, SQUARES},
+};
+use crate::learn::state_to_feature::{action_family, ACTION_SIZE, FEATURE_VERSION, STATE_SIZE};
+
+const FORMAT: &str = "cerke-dqn-torchscript";
+
+// stored next to the module code as a TorchScript extra file, which
+// `torch.jit.load(path, _extra_files={"layout.json": ""})` or `torch::jit::load` return
+pub const LAYOUT: &str = "layout.json";
+
+// contiguous ranges of action indices by family
+fn action_ranges() -> Vec<Value> {
+    let mut ranges: Vec<(String, usize, usize)> = Vec::new();
+    for index in 0..ACTION_SIZE {
+        let family = format!("{:?}", action_family(index));
+        match ranges.last_mut() {
+            Some((name, _, end)) if *name == family => *end = index + 1,
+            _ => ranges.push((family, index, index + 1)),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(|(family, start, end)| json!({ "family": family, "start": start, "end": end }))
+        .collect()
+}
+
+fn layout(spec: &NetworkSpec, iteration: i64) -> Value {
+    json!({
+        "format": FORMAT,
+        "state_size": STATE_SIZE,
+        "action_size": ACTION_SIZE,
+        "feature_version": FEATURE_VERSION,
+        "rule_config": rule_config(),
+        "iteration": iteration,
+        "network": spec.to_json(),
+        // square-major board planes followed by the hand features
+        "state_layout": {
+            "squares": SQUARES,
+            "planes": PLANES,
+            "hand_features": HAND_FEATURES,
+        },
+        "actions": action_ranges(),
+        "inputs": [
+            { "name": "states", "dtype": "float32", "shape": ["batch", STATE_SIZE] },
+            { "name": "legal", "dtype": "float32", "shape": ["batch", ACTION_SIZE], "values": "1 for a legal action, 0 otherwise" },
+        ],
+        "outputs": [
+            { "name": "q", "shape": ["batch", ACTION_SIZE] },
+            { "name": "distribution", "shape": ["batch", ACTION_SIZE, spec.returns.atoms()] },
+        ],
+    })
+}
+
+// traces the network, whose variables live on the CPU, in inference mode and writes it with its
+// layout as a TorchScript module
+pub(crate) fn export(net: &dyn QModule, spec: &NetworkSpec, path: &str, iteration: i64) -> Result<()> {
+    let returns = spec.returns;
+    let states = Tensor::zeros(&[1, STATE_SIZE as i64], (Kind::Float, Device::Cpu));
+    let legal = Tensor::ones(&[1, ACTION_SIZE as i64], (Kind::Float, Device::Cpu));
+    let module = CModule::create_by_tracing("CerkeQNet", "forward", &[states, legal], &mut |inputs| {
+        let raw = net.forward_q(&inputs[0], &inputs[1], false, false);
+        vec![returns.expected(&raw), returns.distribution(&raw)]
+    })?;
+    module.save(path)?;
+    add_extra_file(path, LAYOUT, &serde_json::to_string_pretty(&layout(spec, iteration))?)
+        .with_context(|| format!("cannot write the layout into {}", path))
+}
+
+// TorchScript archives keep everything under one top-level directory, extra files in its `extra/`
+fn archive_prefix(path: &str) -> Result<String> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let first = archive.by_index(0)?;
+    let prefix = first.name().split('/').next().unwrap_or_default();
+    Ok(prefix.to_string())
+}
+
+fn add_extra_file(path: &str, name: &str, contents: &str) -> Result<()> {
+    let prefix = archive_prefix(path)?;
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut archive = zip::ZipWriter::new_append(file)?;
+    let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    archive.start_file(format!("{}/extra/{}", prefix, name), options)?;
+    archive.write_all(contents.as_bytes())?;
+    archive.finish()?;
+    Ok(())
+}
+
+// the layout of a module written by `export`
+pub fn read_layout(path: &str) -> Result<Value> {
+    let prefix = archive_prefix(path)?;
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut file = archive
+        .by_name(&format!("{}/extra/{}", prefix, LAYOUT))
+        .with_context(|| format!("{} has no {}", path, LAYOUT))?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    Ok(serde_json::from_str(&text)?)
+}
+
+#[test]
+fn test_torchscript_export() {
+    use super::brain::{Brain, QNet};
+    use super::network::Returns;
+    use crate::learn::temp_file::TempFile;
+
+    let spec = NetworkSpec {
+        widths: vec![32],
+        returns: Returns::Quantile { quantiles: 4 },
+        ..NetworkSpec::default()
+    };
+    let mut qnet = QNet::with_spec(spec);
+    qnet.update_hard();
+
+    let file = TempFile::new("qnet.pt");
+    let path = file.path();
+    qnet.export_torchscript(path, 3).unwrap();
+
+    let layout = read_layout(path).unwrap();
+    assert_eq!(layout["state_size"], json!(STATE_SIZE));
+    assert_eq!(layout["action_size"], json!(ACTION_SIZE));
+    assert_eq!(layout["iteration"], json!(3));
+    assert_eq!(NetworkSpec::from_json(&layout["network"]).unwrap(), *qnet.spec());
+
+    let state: Vec<f32> = (0..STATE_SIZE).map(|i| (i % 7 == 0) as i32 as f32).collect();
+    let legal: Vec<i8> = (0..ACTION_SIZE).map(|i| (i % 3 == 0) as i8).collect();
+    let expected = qnet.forward(vec![&state[..]], vec![&legal[..]]).unwrap().pop().unwrap();
+
+    // the module runs on the CPU whatever device the network trained on
+    let module = CModule::load_on_device(path, Device::Cpu).unwrap();
+    let states = Tensor::of_slice(&state).view([1, -1]);
+    let masks = Tensor::of_slice(&legal).view([1, -1]).to_kind(Kind::Float);
+    let q = match module.forward_is(&[states.into(), masks.into()]).unwrap() {
+        tch::IValue::Tuple(outputs) => match &outputs[0] {
+            tch::IValue::Tensor(q) => {
+                assert_eq!(q.device(), Device::Cpu);
+                Vec::<f32>::from(q.view([-1]))
+            }
+            _ => panic!("q is not a tensor"),
+        },
+        _ => panic!("the module does not return a tuple"),
+    };
+    for i in (0..ACTION_SIZE).filter(|i| legal[*i] == 1) {
+        assert!((q[i] - expected[i]).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{collections::VecDeque, num::NonZeroUsize};
+
+pub struct Step<S, A> {
+    pub state: S,
+    pub action: A,
+    // what the player earned between this decision and their next one (or the end of the game)
+    pub reward: f32,
+}
+
+// a complete game, split into the decisions of each side
+pub struct Episode<S, A> {
+    pub sides: [Vec<Step<S, A>>; 2],
+    // reward of the move that ended the game, seen by the side that made it
+    pub finish: f32,
+    pub finishing_side: usize,
+}
+
+impl<S, A> Episode<S, A> {
+    pub fn new() -> Self {
+        Self {
+            sides: [Vec::new(), Vec::new()],
+            finish: 0f32,
+            finishing_side: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sides[0].len() + self.sides[1].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // discounted Monte-Carlo return of every decision of `side`
+    pub fn returns(&self, side: usize, gamma: f32) -> Vec<f32> {
+        let steps = &self.sides[side];
+        let mut returns = vec![0f32; steps.len()];
+        let mut g = 0f32;
+        for (i, step) in steps.iter().enumerate().rev() {
+            g = step.reward + gamma * g;
+            returns[i] = g;
+        }
+        returns
+    }
+
+    // lambda-returns of `side`, where `values[i]` estimates the value of `sides[side][i].state`
+    pub fn lambda_returns(&self, side: usize, gamma: f32, lambda: f32, values: &[f32]) -> Vec<f32> {
+        let steps = &self.sides[side];
+        assert_eq!(steps.len(), values.len());
+        let mut returns = vec![0f32; steps.len()];
+        let mut g = 0f32;
+        for (i, step) in steps.iter().enumerate().rev() {
+            g = if i + 1 == steps.len() {
+                step.reward
+            } else {
+                step.reward + gamma * ((1f32 - lambda) * values[i + 1] + lambda * g)
+            };
+            returns[i] = g;
+        }
+        returns
+    }
+}
+
+impl<S, A> Default for Episode<S, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct EpisodeMemory<S, A> {
+    episodes: VecDeque<Episode<S, A>>,
+    capacity: usize,
+}
+
+impl<S, A> EpisodeMemory<S, A> {
+    pub fn with_capacity(capacity: NonZeroUsize) -> Self {
+        let capacity = capacity.get();
+        Self {
+            episodes: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.episodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.episodes.is_empty()
+    }
+
+    // returns the oldest game when it had to make room
+    pub fn put(&mut self, episode: Episode<S, A>) -> Option<Episode<S, A>> {
+        let evicted = if self.episodes.len() >= self.capacity {
+            self.episodes.pop_front()
+        } else {
+            None
+        };
+        self.episodes.push_back(episode);
+        evicted
+    }
+
+    // oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Episode<S, A>> {
+        self.episodes.iter()
+    }
+
+    // the `n` games that ended with the largest absolute finishing reward
+    pub fn largest_finishes(&self, n: usize) -> Vec<&Episode<S, A>> {
+        let mut episodes: Vec<&Episode<S, A>> = self.episodes.iter().collect();
+        episodes.sort_by(|x, y| y.finish.abs().partial_cmp(&x.finish.abs()).unwrap());
+        episodes.truncate(n);
+        episodes
+    }
+}
+
+#[test]
+fn test_episode_returns() {
+    let mut episode = Episode::new();
+    for reward in [1.0, 0.0, 4.0] {
+        episode.sides[0].push(Step {
+            state: 0,
+            action: 0usize,
+            reward,
+        });
+    }
+    assert_eq!(episode.returns(0, 0.5), vec![2.0, 2.0, 4.0]);
+    assert_eq!(episode.lambda_returns(0, 0.5, 1.0, &[9.0, 9.0, 9.0]), vec![2.0, 2.0, 4.0]);
+    assert_eq!(episode.lambda_returns(0, 0.5, 0.0, &[0.0, 2.0, 8.0]), vec![2.0, 4.0, 4.0]);
+    assert!(episode.returns(1, 0.5).is_empty());
+
+    let mut memory = EpisodeMemory::with_capacity(NonZeroUsize::new(2).unwrap());
+    let evicted: Vec<f32> = [1.0, -20.0, 3.0]
+        .iter()
+        .filter_map(|finish| {
+            let mut episode: Episode<i32, usize> = Episode::new();
+            episode.finish = *finish;
+            memory.put(episode).map(|x| x.finish)
+        })
+        .collect();
+    assert_eq!(evicted, vec![1.0]);
+    assert_eq!(memory.len(), 2);
+    assert_eq!(memory.largest_finishes(1)[0].finish, -20.0);
+}
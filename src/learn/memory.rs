@@ -1,32 +1,192 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
 use rand::{thread_rng, Rng};
 
+use super::sum_tree::SumTree;
+
 #[derive(Clone)]
 pub struct Experience<S, A> {
     pub current_state: S,
     pub action: A,
     pub next_state: S,
     pub value: f32,
+    // `next_state` is meaningless when the episode ended with this transition
+    pub done: bool,
+    // number of the player's own turns between `current_state` and `next_state`
+    pub steps: u32,
+}
+
+impl<S, A> Experience<S, A> {
+    pub fn map_state<T, F: Fn(&S) -> T>(self, f: F) -> Experience<T, A> {
+        Experience {
+            current_state: f(&self.current_state),
+            action: self.action,
+            next_state: f(&self.next_state),
+            value: self.value,
+            done: self.done,
+            steps: self.steps,
+        }
+    }
+}
+
+pub trait Features {
+    const WIDTH: usize;
+
+    // `out` is zeroed and exactly `WIDTH` long
+    fn write_features(&self, out: &mut [f32]);
+}
+
+// copies of sampled experiences with their states laid out row-major, ready to become
+// tensors; a batch borrows nothing, so the memory can take puts again while it is used
+pub struct Batch<S, A> {
+    pub indices: Vec<usize>,
+    // put order of each experience, unique over the lifetime of the memory
+    pub stamps: Vec<u64>,
+    pub weights: Vec<f32>,
+    pub experiences: Vec<Experience<S, A>>,
+    pub states: Vec<f32>,
+    // rows of terminal experiences are left zeroed
+    pub next_states: Vec<f32>,
+}
+
+impl<S, A> Batch<S, A> {
+    pub fn len(&self) -> usize {
+        self.experiences.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.experiences.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Eviction {
+    // overwrite the oldest slot, ring-buffer style
+    Fifo,
+    // keep a uniform sample of every experience ever put
+    Reservoir,
+    // overwrite the oldest of `tournament` randomly chosen slots
+    OldestBiased { tournament: usize },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Sampling {
+    Uniform,
+    Prioritized { alpha: f32, beta: f32, epsilon: f32 },
 }
 
 pub struct Memory<S, A> {
     memory: Vec<Experience<S, A>>,
+    stamps: Vec<u64>,
     capacity: usize,
+    eviction: Eviction,
+    next: usize,
+    seen: u64,
+    sampling: Sampling,
+    priorities: SumTree,
+    max_priority: f64,
 }
 
 impl<S, A> Memory<S, A> {
     pub fn new() -> Self {
+        Self::with_sampling(Sampling::Uniform)
+    }
+
+    pub fn with_sampling(sampling: Sampling) -> Self {
+        Self::with_capacity(50000, Eviction::Fifo, sampling)
+    }
+
+    pub fn with_capacity(capacity: usize, eviction: Eviction, sampling: Sampling) -> Self {
+        assert!(capacity > 0);
+        // the buffers grow with the experiences put, up to `capacity`
         Self {
             memory: Vec::new(),
-            capacity: 50000,
+            stamps: Vec::new(),
+            capacity,
+            eviction,
+            next: 0,
+            seen: 0,
+            sampling,
+            priorities: SumTree::new(capacity),
+            max_priority: 1f64,
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // (number of puts since the experience was stored, experience), in slot order
+    pub fn iter_with_age(&self) -> impl Iterator<Item = (u64, &Experience<S, A>)> {
+        let seen = self.seen;
+        self.stamps
+            .iter()
+            .zip(self.memory.iter())
+            .map(move |(stamp, experience)| (seen - 1 - stamp, experience))
+    }
+
+    // oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Experience<S, A>> {
+        let mut indices: Vec<usize> = (0..self.memory.len()).collect();
+        indices.sort_by_key(|index| self.stamps[*index]);
+        indices.into_iter().map(move |index| &self.memory[index])
+    }
+
     pub fn put(&mut self, item: Experience<S, A>) {
-        if self.memory.len() < self.capacity {
+        let stamp = self.seen;
+        self.seen += 1;
+
+        let index = if self.memory.len() < self.capacity {
+            if self.memory.len() == self.memory.capacity() {
+                let grown = (self.memory.capacity() * 2).max(1024).min(self.capacity);
+                self.memory.reserve_exact(grown - self.memory.len());
+                self.stamps.reserve_exact(grown - self.stamps.len());
+            }
             self.memory.push(item);
+            self.stamps.push(stamp);
+            self.memory.len() - 1
         } else {
-            let index = thread_rng().gen_range(0..self.capacity);
-            self.memory.insert(index, item);
+            let index = match self.evict(stamp) {
+                Some(index) => index,
+                None => return,
+            };
+            self.memory[index] = item;
+            self.stamps[index] = stamp;
+            index
+        };
+        self.priorities.set(index, self.max_priority);
+    }
+
+    fn evict(&mut self, stamp: u64) -> Option<usize> {
+        let mut rng = thread_rng();
+        match self.eviction {
+            Eviction::Fifo => {
+                let index = self.next;
+                self.next = (self.next + 1) % self.capacity;
+                Some(index)
+            }
+            Eviction::Reservoir => {
+                let index = rng.gen_range(0..=stamp);
+                if index < self.capacity as u64 {
+                    Some(index as usize)
+                } else {
+                    None
+                }
+            }
+            Eviction::OldestBiased { tournament } => (0..tournament.max(1))
+                .map(|_| rng.gen_range(0..self.capacity))
+                .min_by_key(|index| self.stamps[*index]),
         }
     }
 
@@ -34,4 +194,359 @@ impl<S, A> Memory<S, A> {
         let index = thread_rng().gen_range(0..self.memory.len());
         self.memory.get(index).unwrap()
     }
+
+    // returns (slot index, experience, importance-sampling weight) for each sample.
+    // weights are normalized by the largest weight in the batch. draws landing on a slot of
+    // priority 0 are dropped, so fewer than `n` samples may come back
+    pub fn sample_with_weights(&self, n: usize) -> Vec<(usize, &Experience<S, A>, f32)> {
+        if self.memory.is_empty() {
+            return Vec::new();
+        }
+        let mut rng = thread_rng();
+        match self.sampling {
+            Sampling::Uniform => (0..n)
+                .map(|_| {
+                    let index = rng.gen_range(0..self.memory.len());
+                    (index, &self.memory[index], 1f32)
+                })
+                .collect(),
+            Sampling::Prioritized { beta, .. } => {
+                let total = self.priorities.total();
+                if total <= 0f64 {
+                    return Vec::new();
+                }
+                let segment = total / n as f64;
+                let len = self.memory.len() as f64;
+
+                let mut samples: Vec<(usize, &Experience<S, A>, f32)> = (0..n)
+                    .filter_map(|i| {
+                        let value = segment * (i as f64 + rng.gen::<f64>());
+                        let index = self.priorities.find(value);
+                        let probability = self.priorities.get(index) / total;
+                        if index >= self.memory.len() || probability <= 0f64 {
+                            return None;
+                        }
+                        let weight = (len * probability).powf(-beta as f64) as f32;
+                        Some((index, &self.memory[index], weight))
+                    })
+                    .collect();
+
+                let max_weight = samples.iter().map(|x| x.2).fold(0f32, f32::max);
+                if max_weight > 0f32 && max_weight.is_finite() {
+                    for sample in samples.iter_mut() {
+                        sample.2 /= max_weight;
+                    }
+                }
+                samples
+            }
+        }
+    }
+
+    pub fn sample_batch(&self, n: usize) -> Batch<S, A>
+    where
+        S: Features + Clone,
+        A: Clone,
+    {
+        let samples = self.sample_with_weights(n);
+        let mut states = vec![0f32; samples.len() * S::WIDTH];
+        let mut next_states = vec![0f32; samples.len() * S::WIDTH];
+        let mut batch = Batch {
+            indices: Vec::with_capacity(samples.len()),
+            stamps: Vec::with_capacity(samples.len()),
+            weights: Vec::with_capacity(samples.len()),
+            experiences: Vec::with_capacity(samples.len()),
+            states: Vec::new(),
+            next_states: Vec::new(),
+        };
+
+        for (i, (index, experience, weight)) in samples.into_iter().enumerate() {
+            let row = i * S::WIDTH..(i + 1) * S::WIDTH;
+            experience.current_state.write_features(&mut states[row.clone()]);
+            if !experience.done {
+                experience.next_state.write_features(&mut next_states[row]);
+            }
+            batch.indices.push(index);
+            batch.stamps.push(self.stamps[index]);
+            batch.weights.push(weight);
+            batch.experiences.push(experience.clone());
+        }
+        batch.states = states;
+        batch.next_states = next_states;
+        batch
+    }
+
+    pub fn update_priority(&mut self, index: usize, td_error: f32) {
+        if let Sampling::Prioritized { alpha, epsilon, .. } = self.sampling {
+            let priority = ((td_error.abs() + epsilon) as f64).powf(alpha as f64);
+            self.priorities.set(index, priority);
+            self.max_priority = self.max_priority.max(priority);
+        }
+    }
+}
+
+// a handle to a `Memory` that actor threads put into while a learner samples from it
+pub struct SharedMemory<S, A> {
+    inner: Arc<Mutex<Memory<S, A>>>,
+}
+
+impl<S, A> Clone for SharedMemory<S, A> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<S, A> SharedMemory<S, A> {
+    pub fn new(memory: Memory<S, A>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(memory)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub fn put(&self, item: Experience<S, A>) {
+        self.lock().put(item)
+    }
+
+    // takes the lock once for the whole batch
+    pub fn put_all<I: IntoIterator<Item = Experience<S, A>>>(&self, items: I) {
+        let mut memory = self.lock();
+        for item in items {
+            memory.put(item);
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Memory<S, A>> {
+        self.inner.lock().unwrap()
+    }
+}
+
+struct Pending<S, A> {
+    state: S,
+    action: A,
+    value: f32,
+    steps: u32,
+}
+
+// turns one player's decisions into n-step experiences
+pub struct NStep<S, A> {
+    n: usize,
+    gamma: f32,
+    pending: VecDeque<Pending<S, A>>,
+}
+
+impl<S: Clone, A> NStep<S, A> {
+    pub fn new(n: usize, gamma: f32) -> Self {
+        assert!(n > 0);
+        Self {
+            n,
+            gamma,
+            pending: VecDeque::with_capacity(n),
+        }
+    }
+
+    pub fn latest(&self) -> Option<&S> {
+        self.pending.back().map(|x| &x.state)
+    }
+
+    pub fn push(&mut self, state: S, action: A) {
+        self.pending.push_back(Pending {
+            state,
+            action,
+            value: 0f32,
+            steps: 0,
+        });
+    }
+
+    fn accumulate(&mut self, reward: f32) {
+        let gamma = self.gamma;
+        for item in self.pending.iter_mut() {
+            item.value += gamma.powi(item.steps as i32) * reward;
+            item.steps += 1;
+        }
+    }
+
+    // `reward` is what the player earned between `latest()` and `state`,
+    // which is the player's next decision point
+    pub fn step(&mut self, reward: f32, state: &S) -> Option<Experience<S, A>> {
+        self.accumulate(reward);
+        if self.pending.front()?.steps as usize >= self.n {
+            let item = self.pending.pop_front().unwrap();
+            Some(Experience {
+                current_state: item.state,
+                action: item.action,
+                next_state: state.clone(),
+                value: item.value,
+                done: false,
+                steps: item.steps,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn finish(&mut self, reward: f32) -> Vec<Experience<S, A>> {
+        self.accumulate(reward);
+        self.pending
+            .drain(..)
+            .map(|item| Experience {
+                next_state: item.state.clone(),
+                current_state: item.state,
+                action: item.action,
+                value: item.value,
+                done: true,
+                steps: item.steps,
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_memory_eviction_keeps_capacity() {
+    let experience = |x: i32| Experience {
+        current_state: x,
+        action: 0usize,
+        next_state: x,
+        value: 0f32,
+        done: false,
+        steps: 1,
+    };
+    for eviction in [
+        Eviction::Fifo,
+        Eviction::Reservoir,
+        Eviction::OldestBiased { tournament: 3 },
+    ] {
+        let mut memory = Memory::with_capacity(10, eviction, Sampling::Uniform);
+        for i in 0..1000 {
+            memory.put(experience(i));
+        }
+        assert_eq!(memory.len(), 10);
+        assert_eq!(memory.memory.capacity(), 10);
+    }
+
+    let mut memory = Memory::with_capacity(4, Eviction::Fifo, Sampling::Uniform);
+    for i in 0..6 {
+        memory.put(experience(i));
+    }
+    let mut states: Vec<i32> = memory.memory.iter().map(|x| x.current_state).collect();
+    states.sort();
+    assert_eq!(states, vec![2, 3, 4, 5]);
+}
+
+#[test]
+fn test_n_step_returns() {
+    let mut n_step = NStep::new(2, 0.5);
+    n_step.push(0, 0usize);
+    assert!(n_step.step(1.0, &1).is_none());
+    n_step.push(1, 1usize);
+
+    let experience = n_step.step(2.0, &2).unwrap();
+    assert_eq!(experience.current_state, 0);
+    assert_eq!(experience.next_state, 2);
+    assert_eq!(experience.value, 1.0 + 0.5 * 2.0);
+    assert_eq!(experience.steps, 2);
+    n_step.push(2, 2usize);
+
+    let rest = n_step.finish(4.0);
+    assert_eq!(rest.len(), 2);
+    assert!(rest.iter().all(|x| x.done));
+    assert_eq!(rest[0].current_state, 1);
+    assert_eq!(rest[0].value, 2.0 + 0.5 * 4.0);
+    assert_eq!(rest[1].current_state, 2);
+    assert_eq!(rest[1].value, 4.0);
+    assert_eq!(n_step.latest(), None);
+}
+
+#[cfg(test)]
+impl Features for i32 {
+    const WIDTH: usize = 2;
+
+    fn write_features(&self, out: &mut [f32]) {
+        out[0] = *self as f32;
+        out[1] = -*self as f32;
+    }
+}
+
+#[test]
+fn test_sample_batch_layout() {
+    let mut memory = Memory::with_capacity(8, Eviction::Fifo, Sampling::Uniform);
+    memory.put(Experience {
+        current_state: 3,
+        action: 0usize,
+        next_state: 4,
+        value: 0f32,
+        done: false,
+        steps: 1,
+    });
+    let batch = memory.sample_batch(5);
+    assert_eq!(batch.len(), 5);
+    assert_eq!(batch.states, [3.0, -3.0].repeat(5));
+    assert_eq!(batch.next_states, [4.0, -4.0].repeat(5));
+    assert_eq!(batch.weights, vec![1.0; 5]);
+}
+
+#[test]
+fn test_prioritized_sampling_edges() {
+    let sampling = Sampling::Prioritized {
+        alpha: 1.0,
+        beta: 0.4,
+        epsilon: 0.0,
+    };
+    let mut memory = Memory::with_capacity(8, Eviction::Fifo, sampling);
+    assert!(memory.sample_with_weights(4).is_empty());
+
+    for i in 0..2 {
+        memory.put(Experience {
+            current_state: i,
+            action: 0usize,
+            next_state: i,
+            value: 0f32,
+            done: true,
+            steps: 1,
+        });
+    }
+    // a TD error of 0 without epsilon leaves the first slot with no priority at all
+    memory.update_priority(0, 0.0);
+    let samples = memory.sample_with_weights(16);
+    assert!(!samples.is_empty());
+    assert!(samples.iter().all(|(index, _, weight)| *index == 1 && *weight == 1.0));
+}
+
+#[test]
+fn test_shared_memory_concurrent_put() {
+    let memory = SharedMemory::new(Memory::with_capacity(
+        1000,
+        Eviction::Fifo,
+        Sampling::Uniform,
+    ));
+    let actors: Vec<_> = (0..4)
+        .map(|actor| {
+            let memory = memory.clone();
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    memory.put_all(vec![Experience {
+                        current_state: actor * 100 + i,
+                        action: 0usize,
+                        next_state: 0,
+                        value: 0f32,
+                        done: true,
+                        steps: 1,
+                    }]);
+                }
+            })
+        })
+        .collect();
+    for actor in actors {
+        actor.join().unwrap();
+    }
+    assert_eq!(memory.len(), 400);
+    assert_eq!(memory.lock().sample_batch(10).len(), 10);
 }
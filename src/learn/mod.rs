@@ -1,4 +1,8 @@
 pub mod cerke;
 pub mod communicator;
+pub mod episode;
 pub mod memory;
 pub mod state_to_feature;
+pub mod sum_tree;
+#[cfg(test)]
+pub(crate) mod temp_file;
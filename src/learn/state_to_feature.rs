@@ -6,10 +6,12 @@ use cetkaik_core::{
 };
 use cetkaik_full_state_transition::{message::{
     AfterHalfAcceptance, InfAfterStep, NormalMove, PureMove,
-}, state::Phase};
+}, state::Phase, Config};
 
 pub const STATE_SIZE: usize = 42 * 81 + 2 * 2 * (2 + 9 + 3 + 3 + 3 + 3 + 3 + 3 + 3 + 2);
 pub const ACTION_SIZE: usize = 20 * 81 + 81 * 81 + 81 + 3; // hand + normal move + half_acceptance + pass + tymok + taxot
+// bump whenever the meaning of a feature or an action index changes, so that old checkpoints and replay buffers are refused
+pub const FEATURE_VERSION: u32 = 1;
 
 fn coord_to_num(coord: &Coord) -> usize {
     (match coord.0 {
@@ -154,66 +156,73 @@ fn get_piece_key(piece: &NonTam2Piece) -> usize {
         })
 }
 
-fn state_a_to_feature(
+// piece index of each square, followed by the hand counts of the turn player and of the other player
+struct FeatureIndices {
+    board: [usize; 81],
+    hands: [usize; 40],
+}
+
+fn state_a_to_indices(
     board: &HashMap<Coord, Piece>,
     a_side_hop1zuo1: &Vec<NonTam2Piece>,
     ia_side_hop1zuo1: &Vec<NonTam2Piece>,
     whose_turn: &Side,
-) -> [f32; STATE_SIZE] {
-    /*  42 * 81 + 2 * 2 * (
-        2 + 9 + 3 + 3 + 3 + 3 + 3 + 3 + 3 + 2
-    ) */
-
-    let mut res = [0f32; STATE_SIZE];
+) -> FeatureIndices {
     let (turn_player, nonturn_player) = match whose_turn {
         Side::ASide => (a_side_hop1zuo1, ia_side_hop1zuo1),
         Side::IASide => (ia_side_hop1zuo1, a_side_hop1zuo1),
     };
-    let mut hm = [0usize; 81];
+    let mut res = FeatureIndices {
+        board: [0usize; 81],
+        hands: [0usize; 40],
+    };
     for (c, p) in board.iter() {
-        hm[coord_to_num(c)] = piece_to_num(p, &whose_turn);
+        res.board[coord_to_num(c)] = piece_to_num(p, &whose_turn);
+    }
+    for p in turn_player.iter() {
+        res.hands[get_piece_key(p)] += 1;
     }
+    for p in nonturn_player.iter() {
+        res.hands[20 + get_piece_key(p)] += 1;
+    }
+    res
+}
+
+fn indices_to_feature(indices: &FeatureIndices, res: &mut [f32]) {
+    /*  42 * 81 + 2 * 2 * (
+        2 + 9 + 3 + 3 + 3 + 3 + 3 + 3 + 3 + 2
+    ) */
+
     for i in 0..81 {
-        res[i * 42 + hm[i]] = 1f32;
+        res[i * 42 + indices.board[i]] = 1f32;
     }
 
     let offset = 42 * 81;
-    let mut hm = [0usize; 20];
-    for p in turn_player.iter() {
-        hm[get_piece_key(p)] += 1;
-    }
     for i in 0..20 {
-        res[offset + KEY_TO_OFFSET[i] + hm[i]] = 1f32;
-    }
-
-    let mut hm = [0usize; 20];
-    for p in nonturn_player.iter() {
-        hm[get_piece_key(p)] += 1;
+        res[offset + KEY_TO_OFFSET[i] + indices.hands[i]] = 1f32;
     }
 
     let offset = 42 * 81 + 2 * (2 + 9 + 3 + 3 + 3 + 3 + 3 + 3 + 3 + 2);
     for i in 0..20 {
-        res[offset + KEY_TO_OFFSET[i] + hm[i]] = 1f32;
+        res[offset + KEY_TO_OFFSET[i] + indices.hands[20 + i]] = 1f32;
     }
-
-    res
 }
 
-pub fn state_to_feature(state: &Phase) -> [f32; STATE_SIZE] {
+fn state_to_indices(state: &Phase) -> FeatureIndices {
     match state {
-        Phase::Start(state) => state_a_to_feature(
+        Phase::Start(state) => state_a_to_indices(
             &state.f.board,
             &state.f.a_side_hop1zuo1,
             &state.f.ia_side_hop1zuo1,
             &state.whose_turn,
         ),
-        Phase::AfterCiurl(state) => state_a_to_feature(
+        Phase::AfterCiurl(state) => state_a_to_indices(
             &state.c.f.board,
             &state.c.f.a_side_hop1zuo1,
             &state.c.f.ia_side_hop1zuo1,
             &state.c.whose_turn,
         ),
-        Phase::Moved(state) => state_a_to_feature(
+        Phase::Moved(state) => state_a_to_indices(
             &state.f.board,
             &state.f.a_side_hop1zuo1,
             &state.f.ia_side_hop1zuo1,
@@ -222,6 +231,81 @@ pub fn state_to_feature(state: &Phase) -> [f32; STATE_SIZE] {
     }
 }
 
+pub fn state_to_feature(state: &Phase) -> [f32; STATE_SIZE] {
+    let mut res = [0f32; STATE_SIZE];
+    indices_to_feature(&state_to_indices(state), &mut res);
+    res
+}
+
+// 6 bits per square (piece index < 42) and 4 bits per hand count
+const BOARD_BITS: usize = 6;
+const HAND_BITS: usize = 4;
+pub const PACKED_SIZE: usize = (81 * BOARD_BITS + 40 * HAND_BITS + 7) / 8;
+
+fn write_bits(buf: &mut [u8], pos: usize, width: usize, value: usize) {
+    for b in 0..width {
+        if (value >> b) & 1 == 1 {
+            buf[(pos + b) / 8] |= 1 << ((pos + b) % 8);
+        }
+    }
+}
+
+fn read_bits(buf: &[u8], pos: usize, width: usize) -> usize {
+    let mut value = 0;
+    for b in 0..width {
+        if (buf[(pos + b) / 8] >> ((pos + b) % 8)) & 1 == 1 {
+            value |= 1 << b;
+        }
+    }
+    value
+}
+
+pub fn state_to_packed(state: &Phase) -> [u8; PACKED_SIZE] {
+    let indices = state_to_indices(state);
+    let mut packed = [0u8; PACKED_SIZE];
+    for (i, p) in indices.board.iter().enumerate() {
+        write_bits(&mut packed, i * BOARD_BITS, BOARD_BITS, *p);
+    }
+    let offset = 81 * BOARD_BITS;
+    for (i, n) in indices.hands.iter().enumerate() {
+        write_bits(&mut packed, offset + i * HAND_BITS, HAND_BITS, *n);
+    }
+    packed
+}
+
+fn unpack_indices(packed: &[u8; PACKED_SIZE]) -> FeatureIndices {
+    let mut indices = FeatureIndices {
+        board: [0usize; 81],
+        hands: [0usize; 40],
+    };
+    for i in 0..81 {
+        indices.board[i] = read_bits(packed, i * BOARD_BITS, BOARD_BITS);
+    }
+    let offset = 81 * BOARD_BITS;
+    for i in 0..40 {
+        indices.hands[i] = read_bits(packed, offset + i * HAND_BITS, HAND_BITS);
+    }
+    indices
+}
+
+// whether every packed index falls inside its one-hot block, each hand count inside the
+// block of its own piece
+pub fn packed_is_valid(packed: &[u8; PACKED_SIZE]) -> bool {
+    let indices = unpack_indices(packed);
+    let hand_block = 2 * (2 + 9 + 3 + 3 + 3 + 3 + 3 + 3 + 3 + 2);
+    indices.board.iter().all(|p| *p < 42)
+        && indices.hands.iter().enumerate().all(|(i, n)| {
+            let key = i % 20;
+            let end = KEY_TO_OFFSET.get(key + 1).copied().unwrap_or(hand_block);
+            *n < end - KEY_TO_OFFSET[key]
+        })
+}
+
+// `res` must be zeroed and `STATE_SIZE` long
+pub fn packed_to_feature(packed: &[u8; PACKED_SIZE], res: &mut [f32]) {
+    indices_to_feature(&unpack_indices(packed), res);
+}
+
 #[test]
 fn test_init_state() {
     let (mut e, _) = cetkaik_full_state_transition::initial_state().choose();
@@ -247,6 +331,23 @@ fn test_init_state() {
     assert_eq!(v, expect);
 }
 
+#[test]
+fn test_packed_state() {
+    let (e, _) = cetkaik_full_state_transition::initial_state().choose();
+    let state = Phase::Start(e);
+    let packed = state_to_packed(&state);
+    assert!(packed_is_valid(&packed));
+
+    let mut res = [0f32; STATE_SIZE];
+    packed_to_feature(&packed, &mut res);
+    assert_eq!(res.to_vec(), state_to_feature(&state).to_vec());
+
+    // two of the first piece of the opponent's hand would land in the block of the second
+    let mut corrupted = packed;
+    write_bits(&mut corrupted, 81 * BOARD_BITS + 20 * HAND_BITS, HAND_BITS, 2);
+    assert!(!packed_is_valid(&corrupted));
+}
+
 pub fn candidates_to_mask(
     hop1zuo1_candidates: &Vec<PureMove>,
     candidates: &Vec<PureMove>,
@@ -317,7 +418,7 @@ pub fn candidates_to_mask(
                         },
                         &false,
                     ) * 81
-                    + coord_to_num(dest)];
+                    + coord_to_num(dest)] = 1;
             }
             _ => unreachable!(),
         }
@@ -458,3 +559,38 @@ pub fn get_tymok_candidate_by_index(index: usize) -> bool {
         unreachable!()
     }
 }
+
+pub fn phase_to_mask(state: &Phase) -> [i8; ACTION_SIZE] {
+    match state {
+        Phase::Start(state) => {
+            let (hop1zuo1_candidates, candidates) =
+                state.get_candidates(Config::cerke_online_alpha());
+            candidates_to_mask(&hop1zuo1_candidates, &candidates)
+        }
+        Phase::AfterCiurl(state) => {
+            let candidates = state.get_candidates(Config::cerke_online_alpha());
+            afterhalf_candidates_to_mask(&candidates)
+        }
+        Phase::Moved(_state) => tymok_mask(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActionFamily {
+    BoardMove,
+    Drop,
+    AfterHalf,
+    TymokTaxot,
+}
+
+pub fn action_family(index: usize) -> ActionFamily {
+    if index < 81 * 81 {
+        ActionFamily::BoardMove
+    } else if index < 20 * 81 + 81 * 81 {
+        ActionFamily::Drop
+    } else if index <= 20 * 81 + 81 * 81 + 81 {
+        ActionFamily::AfterHalf
+    } else {
+        ActionFamily::TymokTaxot
+    }
+}
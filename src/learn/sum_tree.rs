@@ -0,0 +1,68 @@
+pub struct SumTree {
+    tree: Vec<f64>,
+    leaves: usize,
+}
+
+impl SumTree {
+    pub fn new(capacity: usize) -> Self {
+        let leaves = capacity.max(1).next_power_of_two();
+        Self {
+            tree: vec![0f64; 2 * leaves],
+            leaves,
+        }
+    }
+
+    pub fn total(&self) -> f64 {
+        self.tree[1]
+    }
+
+    pub fn get(&self, index: usize) -> f64 {
+        self.tree[self.leaves + index]
+    }
+
+    pub fn set(&mut self, index: usize, priority: f64) {
+        let mut i = self.leaves + index;
+        self.tree[i] = priority;
+        i /= 2;
+        while i >= 1 {
+            self.tree[i] = self.tree[2 * i] + self.tree[2 * i + 1];
+            i /= 2;
+        }
+    }
+
+    // returns the leaf whose prefix-sum interval contains `value`
+    pub fn find(&self, value: f64) -> usize {
+        let mut value = value;
+        let mut i = 1;
+        while i < self.leaves {
+            let left = self.tree[2 * i];
+            if value < left {
+                i *= 2;
+            } else {
+                value -= left;
+                i = 2 * i + 1;
+            }
+        }
+        i - self.leaves
+    }
+}
+
+#[test]
+fn test_sum_tree_find() {
+    let mut tree = SumTree::new(5);
+    tree.set(0, 1.0);
+    tree.set(1, 0.0);
+    tree.set(2, 2.0);
+    tree.set(4, 3.0);
+    assert_eq!(tree.total(), 6.0);
+    assert_eq!(tree.find(0.5), 0);
+    assert_eq!(tree.find(1.0), 2);
+    assert_eq!(tree.find(2.9), 2);
+    assert_eq!(tree.find(3.0), 4);
+    assert_eq!(tree.find(5.9), 4);
+
+    tree.set(2, 0.5);
+    assert_eq!(tree.total(), 4.5);
+    assert_eq!(tree.get(2), 0.5);
+    assert_eq!(tree.find(1.6), 4);
+}
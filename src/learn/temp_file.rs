@@ -0,0 +1,39 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// a path under the temp directory that no other test, in this process or a parallel one,
+// writes to; the file is removed once this is dropped, even when the test fails
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub(crate) fn new(name: &str) -> Self {
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let file = format!("cerke_dqn_{}_{}_{}", std::process::id(), n, name);
+        Self {
+            path: std::env::temp_dir().join(file),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    // another file named after this one, for formats spread over several files
+    pub(crate) fn with_suffix(&self, suffix: &str) -> Self {
+        Self {
+            path: PathBuf::from(format!("{}{}", self.path(), suffix)),
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -1,23 +1,61 @@
 #![feature(vec_retain_mut)]
 pub mod learn;
 
-use std::sync::{Arc, Mutex};
-
+use anyhow::anyhow;
 use cetkaik_full_state_transition::{Config, state::Phase};
 use lazy_static::lazy_static;
 use learn::cerke::environment::Action;
 
-use crate::learn::cerke::agent::CerkeAgent;
+use crate::learn::cerke::policy::Policy;
+#[cfg(feature = "torch")]
+use crate::learn::cerke::service::{InferenceService, ServiceConfig};
+#[cfg(not(feature = "torch"))]
+use crate::learn::cerke::{checkpoint, inference::CpuNet};
+
+// the checkpoint of the agent. the `ai/_learn.vs`/`ai/_target.vs` pair of the first versions
+// is still read by libtorch builds; `migrate_checkpoint ai/ ai/` rewrites it as a checkpoint
+// directory, which also holds the export builds without libtorch read
+const AGENT: &str = "ai/";
+
+// the error is kept so that every call reports it instead of the first one panicking
+#[cfg(feature = "torch")]
+lazy_static! {
+    // concurrent callers share batches instead of queueing for one agent
+    static ref agent: Result<InferenceService, String> =
+        InferenceService::from_checkpoint(AGENT, ServiceConfig::default())
+            .map_err(|error| format!("cannot load the agent from {}: {:#}", AGENT, error));
+}
 
+#[cfg(not(feature = "torch"))]
 lazy_static! {
-    static ref agent: Arc<Mutex<CerkeAgent>> = Arc::new(
-        Mutex::new(
-            CerkeAgent::from_file("ai/".to_string())
-        )
-    );
+    // the export every checkpoint holds, evaluated without libtorch
+    static ref agent: Result<CpuNet, String> =
+        CpuNet::load(&checkpoint::file(AGENT, checkpoint::CPU_NET).to_string_lossy()).map_err(|error| {
+            format!(
+                "cannot load the agent from {}: {:#} (a checkpoint from before the export is \
+                 converted by `migrate_checkpoint {} {}` in a libtorch build)",
+                AGENT, error, AGENT, AGENT
+            )
+        });
+}
+
+fn loaded() -> anyhow::Result<&'static impl Policy> {
+    agent.as_ref().map_err(|error| anyhow!("{}", error))
+}
+
+pub fn bot_action(state: Phase, confin: Config) -> Action {
+    try_bot_action(state, confin).expect("cannot load the agent")
 }
 
-pub fn bot_action(state: Phase, _confin: Config) -> Action {
-    let (action, _) = agent.lock().unwrap().select_action(&state);
-    action
-}
\ No newline at end of file
+// `bot_action` reporting an agent that failed to load instead of panicking
+pub fn try_bot_action(state: Phase, _confin: Config) -> anyhow::Result<Action> {
+    let (action, _) = loaded()?.select_action(&state);
+    Ok(action)
+}
+
+// the action with how much the heads of an ensemble disagree on its value
+pub fn bot_action_with_uncertainty(state: Phase, _confin: Config) -> anyhow::Result<(Action, f32)> {
+    let policy = loaded()?;
+    let (action, index) = policy.select_action(&state);
+    Ok((action, policy.uncertainty(&state)[index]))
+}
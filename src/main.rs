@@ -1,10 +1,28 @@
 use std::time::Instant;
 
-use cerke_dqn::learn::cerke::agent::CerkeAgent;
+use cerke_dqn::learn::cerke::agent::{AgentConfig, CerkeAgent};
 use cerke_dqn::learn::cerke::environment::ParallelCerke;
+use cerke_dqn::learn::cerke::network::NetworkSpec;
+use cerke_dqn::learn::cerke::optimizer::OptimizerConfig;
+use cerke_dqn::learn::cerke::service::ServiceConfig;
 
 fn main() {
-    let mut cp = CerkeAgent::new();
+    // optional JSON network spec and optimizer config to train instead of the defaults
+    let mut config = AgentConfig::default();
+    if let Some(path) = std::env::args().nth(1) {
+        config.network = NetworkSpec::load(&path).unwrap();
+    }
+    if let Some(path) = std::env::args().nth(2) {
+        config.optimizer = OptimizerConfig::load(&path).unwrap();
+    }
+    let explore = config.explore;
+    let mut cp = CerkeAgent::with_config(config);
+    // actors act through a batched copy of the target network
+    cp.attach_service(ServiceConfig {
+        explore,
+        ..ServiceConfig::default()
+    })
+    .unwrap();
 
     let now = Instant::now();
     for i in 0..10000 {
@@ -13,6 +31,9 @@ fn main() {
         
         let elapsed_time = now.elapsed();
         println!("{} : {} sec", i + 1, elapsed_time.as_secs_f64());
+        if let Some(metrics) = cp.last_metrics() {
+            println!("{}", metrics.to_json());
+        }
     }
 }
 